@@ -2,6 +2,8 @@ const raidb = require('random-access-idb');
 const storages = {};
 const toBuffer = require('typedarray-to-buffer')
 
+// The database and file names are part of the on-disk format, changing them
+// needs a migration in `src/schema.rs`.
 function initStorage(id) {
     if (!(id in storages)) {
         const raidbCreator = raidb(id);
@@ -11,25 +13,52 @@ function initStorage(id) {
 
 export async function storage_write(id, offset, data) {
     initStorage(id);
-    return new Promise(resolve => {
-        try {
-            storages[id].write(Number(offset), toBuffer(data), (err) => {
-                if (err) throw err;
-                resolve();
-            });
-        } catch(exp) {
-            console.log(exp);
-            throw exp;
+    return new Promise((resolve, reject) => {
+        storages[id].write(Number(offset), toBuffer(data), (err) => {
+            if (err) return reject(err);
+            resolve();
+        });
+    });
+}
+
+// Adjacent and overlapping chunks are joined, later chunks win.
+function coalesce(offsets, chunks) {
+    const sorted = offsets
+        .map((offset, i) => ({ offset: Number(offset), data: chunks[i], order: i }))
+        .sort((a, b) => a.offset - b.offset || a.order - b.order);
+    const runs = [];
+    for (const { offset, data } of sorted) {
+        const last = runs[runs.length - 1];
+        if (!last || offset > last.offset + last.data.length) {
+            runs.push({ offset, data: Uint8Array.from(data) });
+            continue;
         }
+        const end = Math.max(last.offset + last.data.length, offset + data.length);
+        const joined = new Uint8Array(end - last.offset);
+        joined.set(last.data);
+        joined.set(data, offset - last.offset);
+        last.data = joined;
+    }
+    return runs;
+}
 
-    });
+// Write the chunks of a write-back flush, `offsets[i]` is the offset of
+// `chunks[i]`. Adjacent chunks are joined and the runs are written one after
+// the other through the storage's own `write`, so they are ordered with all
+// other requests on the store. The batch is not atomic: if a write fails,
+// the runs before it stay written.
+export async function storage_write_batch(id, offsets, chunks) {
+    const runs = coalesce(Array.from(offsets), Array.from(chunks));
+    for (const { offset, data } of runs) {
+        await storage_write(id, offset, data);
+    }
 }
 
 export async function storage_read(id, offset, length) {
     initStorage(id);
-    return new Promise(resolve => {
+    return new Promise((resolve, reject) => {
         storages[id].read(Number(offset), Number(length), (err, data) => {
-            if (err) throw err;
+            if (err) return reject(err);
             const returnData = data ? data : new Uint8Array();
             resolve(returnData);
         });
@@ -37,15 +66,44 @@ export async function storage_read(id, offset, length) {
 }
 
 export async function storage_del(id, offset, length) {
-    return new Promise(resolve => resolve());
+    initStorage(id);
+    const storage = storages[id];
+    if (typeof storage.del === 'function') {
+        return new Promise((resolve, reject) => {
+            storage.del(Number(offset), Number(length), (err) => {
+                if (err) return reject(err);
+                resolve();
+            });
+        });
+    }
+    // Without a native delete, zero the range so the old data is gone. This
+    // frees no space, deletes reaching the end of a store are truncated on
+    // the Rust side instead.
+    return storage_write(id, offset, new Uint8Array(Number(length)));
 }
 
 export async function storage_truncate(id, length) {
-    return new Promise(resolve => resolve());
+    initStorage(id);
+    const storage = storages[id];
+    if (typeof storage.truncate !== 'function') return;
+    return new Promise((resolve, reject) => {
+        storage.truncate(Number(length), (err) => {
+            if (err) return reject(err);
+            resolve();
+        });
+    });
 }
 
 export async function storage_len(id) {
-    return new Promise(resolve => resolve());
+    initStorage(id);
+    const storage = storages[id];
+    if (typeof storage.stat !== 'function') return 0;
+    return new Promise((resolve, reject) => {
+        storage.stat((err, stat) => {
+            if (err) return reject(err);
+            resolve(stat && stat.size ? stat.size : 0);
+        });
+    });
 };
 
 export async function storage_is_empty(id) {
@@ -55,3 +113,51 @@ export async function storage_is_empty(id) {
 export async function storage_sync_all(id) {
     return new Promise(resolve => resolve());
 }
+
+export async function storage_estimate() {
+    if (!navigator.storage || !navigator.storage.estimate) {
+        return { usage: 0, quota: 0 };
+    }
+    return navigator.storage.estimate();
+}
+
+export async function storage_persist() {
+    if (!navigator.storage || !navigator.storage.persist) return false;
+    return navigator.storage.persist();
+}
+
+export function sleep(ms) {
+    return new Promise(resolve => setTimeout(resolve, ms));
+}
+
+const cryptoKeys = {};
+
+export function crypto_set_key(keyId, key) {
+    cryptoKeys[keyId] = key;
+}
+
+export function crypto_random_bytes(length) {
+    return crypto.getRandomValues(new Uint8Array(length));
+}
+
+export async function crypto_derive_key(passphrase, salt) {
+    const material = await crypto.subtle.importKey(
+        'raw', new TextEncoder().encode(passphrase), 'PBKDF2', false, ['deriveBits']);
+    const bits = await crypto.subtle.deriveBits(
+        { name: 'PBKDF2', salt, iterations: 210000, hash: 'SHA-256' }, material, 256);
+    return new Uint8Array(bits);
+}
+
+export async function crypto_seal(keyId, nonce, aad, data) {
+    const sealed = await crypto.subtle.encrypt(
+        { name: 'AES-GCM', iv: nonce, additionalData: aad },
+        cryptoKeys[keyId], data);
+    return new Uint8Array(sealed);
+}
+
+export async function crypto_open(keyId, nonce, aad, data) {
+    const opened = await crypto.subtle.decrypt(
+        { name: 'AES-GCM', iv: nonce, additionalData: aad },
+        cryptoKeys[keyId], data);
+    return new Uint8Array(opened);
+}
@@ -0,0 +1,315 @@
+//! A portable single-file container for the stores of a feed.
+//!
+//! The archive starts with an 8 byte magic, followed by entries of
+//! `[name length: u8][name][data length: u64 BE][data]`. Entry names follow
+//! the file names of the Node.js hypercore storage, so `key` holds the
+//! public key and `secret_key` the optional secret key.
+//!
+//! Imports are checked against the public key before any store is
+//! replaced, see `Archive::validate`.
+
+use anyhow::{anyhow, bail, Result};
+use hypercore::{PublicKey, SecretKey, Signature, Store};
+use random_access_storage::RandomAccess;
+use std::convert::{TryFrom, TryInto};
+
+use crate::persistence::{namespaced_id, open_store, store_id};
+use crate::schema;
+use crate::tree::{full_roots, has_stored_nodes, roots_hash, stored_node};
+
+const MAGIC: &[u8; 8] = b"HCARCHV1";
+const PUBLIC_KEY_SIZE: u64 = 32;
+const SECRET_KEY_SIZE: u64 = 64;
+/// Size of the signatures store header.
+const SIGNATURES_HEADER_SIZE: usize = 32;
+const SIGNATURE_SIZE: usize = 64;
+
+/// The stores that are archived under their own name.
+const STORES: [Store; 4] = [Store::Tree, Store::Data, Store::Bitfield, Store::Signatures];
+
+/// An archived feed.
+#[derive(Debug, Default)]
+pub struct Archive {
+    entries: Vec<(String, Vec<u8>)>,
+}
+
+impl Archive {
+    pub fn get(&self, name: &str) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, data)| &data[..])
+    }
+
+    fn push(&mut self, name: &str, data: Vec<u8>) {
+        self.entries.push((name.to_string(), data));
+    }
+
+    /// The hex encoded public key of the archived feed.
+    pub fn public_key(&self) -> Result<String> {
+        let key = self
+            .get("key")
+            .ok_or_else(|| anyhow!("Archive has no key entry"))?;
+        Ok(hex::encode(key))
+    }
+
+    /// Check that the archive holds a complete feed that belongs to its
+    /// public key: an entry for every store, tree roots that match the
+    /// latest signature, and a secret key that matches the public key.
+    pub fn validate(&self) -> Result<()> {
+        for store in STORES.iter() {
+            let id = store_id(store);
+            if self.get(id).is_none() {
+                bail!("Archive has no {} entry", id);
+            }
+        }
+        let key = self
+            .get("key")
+            .ok_or_else(|| anyhow!("Archive has no key entry"))?;
+        let public_key =
+            PublicKey::from_bytes(key).map_err(|_| anyhow!("Invalid public key in archive"))?;
+        if let Some(secret_key) = self.get("secret_key") {
+            // The secret key is the seed followed by the public key.
+            if secret_key.len() as u64 != SECRET_KEY_SIZE || &secret_key[32..] != key {
+                bail!("The secret key in the archive doesn't match its public key");
+            }
+            let secret = SecretKey::from_bytes(&secret_key[..32])
+                .map_err(|_| anyhow!("Invalid secret key in archive"))?;
+            if PublicKey::from(&secret).as_bytes()[..] != key[..] {
+                bail!("The secret key in the archive doesn't match its public key");
+            }
+        }
+        self.verify_signature(&public_key)
+    }
+
+    /// Check the tree roots of the longest signed length against their
+    /// signature.
+    fn verify_signature(&self, public_key: &PublicKey) -> Result<()> {
+        let tree = self.get(store_id(&Store::Tree)).unwrap_or(&[]);
+        let signatures = self.get(store_id(&Store::Signatures)).unwrap_or(&[]);
+        let latest = signatures
+            .get(SIGNATURES_HEADER_SIZE..)
+            .unwrap_or(&[])
+            .chunks_exact(SIGNATURE_SIZE)
+            .enumerate()
+            .rev()
+            .find(|(_, signature)| signature.iter().any(|byte| *byte != 0));
+        let (head, signature) = match latest {
+            Some(latest) => latest,
+            // Only an empty feed has nothing signed.
+            None if !has_stored_nodes(tree) => return Ok(()),
+            None => bail!("Archive has tree nodes but no signature"),
+        };
+        let mut roots = vec![];
+        for index in full_roots(2 * (head as u64 + 1)) {
+            let (hash, size) = stored_node(tree, index)
+                .ok_or_else(|| anyhow!("Archive has no tree root {}", index))?;
+            roots.push((index, hash, size));
+        }
+        let signature =
+            Signature::try_from(signature).map_err(|_| anyhow!("Invalid signature in archive"))?;
+        public_key
+            .verify_strict(&roots_hash(&roots), &signature)
+            .map_err(|_| anyhow!("The tree in the archive doesn't match its signature"))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        for (name, data) in &self.entries {
+            bytes.push(name.len() as u8);
+            bytes.extend_from_slice(name.as_bytes());
+            bytes.extend_from_slice(&(data.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(data);
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < MAGIC.len() || &bytes[..MAGIC.len()] != MAGIC {
+            bail!("Not a feed archive");
+        }
+        let mut archive = Archive::default();
+        let mut rest = &bytes[MAGIC.len()..];
+        while !rest.is_empty() {
+            let name = take(&mut rest, usize::from(rest[0]), 1)
+                .ok_or_else(|| anyhow!("Truncated archive entry name"))?;
+            let name = std::str::from_utf8(name)?;
+            let data_len =
+                take(&mut rest, 8, 0).ok_or_else(|| anyhow!("Truncated archive entry {}", name))?;
+            let data_len = u64::from_be_bytes(data_len.try_into()?);
+            // Entries claiming more bytes than the archive has are rejected
+            // before anything is allocated.
+            let data = usize::try_from(data_len)
+                .ok()
+                .and_then(|data_len| take(&mut rest, data_len, 0))
+                .ok_or_else(|| anyhow!("Truncated archive entry {}", name))?;
+            archive.push(name, data.to_vec());
+        }
+        Ok(archive)
+    }
+}
+
+async fn read_all(id: &str) -> Result<Vec<u8>> {
+    let mut proxy = open_store(id).await?;
+    let len = proxy.len().await.map_err(|err| anyhow!(err))?;
+    proxy.read(0, len).await.map_err(|err| anyhow!(err))
+}
+
+/// Read the stores of the feed in `namespace` into an archive. The caller
+/// has to make sure the feed is not written to meanwhile.
+pub async fn export(namespace: &str, include_secret_key: bool) -> Result<Archive> {
+    let mut archive = Archive::default();
+    for store in STORES.iter() {
+        let id = store_id(store);
+        archive.push(id, read_all(&namespaced_id(namespace, id)).await?);
+    }
+    let keypair = read_all(&namespaced_id(namespace, store_id(&Store::Keypair))).await?;
+    if (keypair.len() as u64) < PUBLIC_KEY_SIZE {
+        bail!("Feed has no public key");
+    }
+    let (public_key, secret_key) = keypair.split_at(PUBLIC_KEY_SIZE as usize);
+    archive.push("key", public_key.to_vec());
+    if include_secret_key && secret_key.len() as u64 >= SECRET_KEY_SIZE {
+        archive.push(
+            "secret_key",
+            secret_key[..SECRET_KEY_SIZE as usize].to_vec(),
+        );
+    }
+    Ok(archive)
+}
+
+async fn replace_store(id: &str, data: &[u8]) -> Result<()> {
+    let mut proxy = open_store(id).await?;
+    proxy.truncate(0).await.map_err(|err| anyhow!(err))?;
+    proxy.write(0, data).await.map_err(|err| anyhow!(err))?;
+    proxy.sync_all().await.map_err(|err| anyhow!(err))
+}
+
+/// Replace the stores of the feed in `namespace` with the contents of an
+/// archive. The feed may not be open while importing. Nothing is replaced
+/// unless the archive passes `Archive::validate`.
+pub async fn import(archive: &Archive, namespace: &str) -> Result<()> {
+    archive.validate()?;
+    // Archives hold the raw store contents of the current format.
+    schema::migrate().await?;
+    let mut keypair = archive
+        .get("key")
+        .ok_or_else(|| anyhow!("Archive has no key entry"))?
+        .to_vec();
+    if keypair.len() as u64 != PUBLIC_KEY_SIZE {
+        bail!("Invalid public key length in archive");
+    }
+    for store in STORES.iter() {
+        let id = store_id(store);
+        let data = archive
+            .get(id)
+            .ok_or_else(|| anyhow!("Archive has no {} entry", id))?;
+        replace_store(&namespaced_id(namespace, id), data).await?;
+    }
+    if let Some(secret_key) = archive.get("secret_key") {
+        keypair.extend_from_slice(secret_key);
+    }
+    replace_store(
+        &namespaced_id(namespace, store_id(&Store::Keypair)),
+        &keypair,
+    )
+    .await
+}
+
+/// Split `len` bytes after a prefix of `skip` bytes off `rest`. Returns
+/// `None` if `rest` is too short.
+fn take<'a>(rest: &mut &'a [u8], len: usize, skip: usize) -> Option<&'a [u8]> {
+    let end = skip.checked_add(len)?;
+    let taken = rest.get(skip..end)?;
+    *rest = &rest[end..];
+    Some(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The compressed Ed25519 base point, a valid public key.
+    const KEY: [u8; 32] = [
+        0x58, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+        0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66, 0x66,
+        0x66, 0x66,
+    ];
+
+    fn archive() -> Archive {
+        let mut archive = Archive::default();
+        archive.push("tree", vec![1; 72]);
+        archive.push("data", b"hello".to_vec());
+        archive.push("bitfield", vec![]);
+        archive.push("signatures", vec![2; 96]);
+        archive.push("key", KEY.to_vec());
+        archive
+    }
+
+    #[test]
+    fn round_trip() {
+        let archive = archive();
+        let decoded = Archive::from_bytes(&archive.to_bytes()).unwrap();
+        assert_eq!(decoded.entries, archive.entries);
+        assert_eq!(decoded.get("data"), Some(&b"hello"[..]));
+        assert_eq!(decoded.get("bitfield"), Some(&[][..]));
+        assert_eq!(decoded.get("secret_key"), None);
+        assert_eq!(decoded.public_key().unwrap(), hex::encode(KEY));
+    }
+
+    #[test]
+    fn reject_bad_magic() {
+        let mut bytes = archive().to_bytes();
+        bytes[0] = b'X';
+        assert!(Archive::from_bytes(&bytes).is_err());
+        assert!(Archive::from_bytes(b"HCAR").is_err());
+        assert!(Archive::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn reject_truncated() {
+        let bytes = archive().to_bytes();
+        // Every cut inside an entry, but not between entries, is an error.
+        let mut boundaries = vec![MAGIC.len()];
+        for (name, data) in &archive().entries {
+            let last = *boundaries.last().unwrap();
+            boundaries.push(last + 1 + name.len() + 8 + data.len());
+        }
+        for len in MAGIC.len()..bytes.len() {
+            let result = Archive::from_bytes(&bytes[..len]);
+            assert_eq!(result.is_ok(), boundaries.contains(&len), "length {}", len);
+        }
+        // A data length past the end of the archive is not allocated.
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(4);
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(Archive::from_bytes(&bytes).is_err());
+    }
+
+    #[test]
+    fn reject_incomplete() {
+        let mut archive = archive();
+        archive.entries.retain(|(name, _)| name != "bitfield");
+        assert!(archive.validate().is_err());
+        let mut archive = self::archive();
+        archive.entries.retain(|(name, _)| name != "key");
+        assert!(archive.validate().is_err());
+    }
+
+    #[test]
+    fn reject_unsigned_tree() {
+        let mut archive = archive();
+        archive.entries.retain(|(name, _)| name != "signatures");
+        archive.push("signatures", vec![0; 96]);
+        let err = archive.validate().unwrap_err();
+        assert!(err.to_string().contains("no signature"), "{}", err);
+    }
+
+    #[test]
+    fn reject_mismatched_secret_key() {
+        let mut archive = archive();
+        archive.push("secret_key", vec![4; 64]);
+        assert!(archive.validate().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Authorization of remote peers by the public key of their handshake.
+
+use js_sys::{Function, Promise};
+use log::*;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+/// Decides which remote peers may replicate with us.
+#[derive(Debug, Clone)]
+pub enum PeerAuth {
+    /// Every peer is allowed.
+    Any,
+    /// Only peers with one of these public keys are allowed.
+    Allowlist(Vec<Vec<u8>>),
+    /// A JS function called with the hex encoded public key. It returns a
+    /// boolean, or a `Promise` resolving to one.
+    Callback(Function),
+}
+
+impl Default for PeerAuth {
+    fn default() -> Self {
+        PeerAuth::Any
+    }
+}
+
+impl PeerAuth {
+    /// Only allow the hex encoded public keys `keys`.
+    pub fn allowlist(keys: &[JsValue]) -> Result<Self, JsValue> {
+        let keys = keys
+            .iter()
+            .map(|key| {
+                let key = key
+                    .as_string()
+                    .ok_or_else(|| js_sys::Error::new("Peer keys must be hex strings"))?;
+                hex::decode(&key).map_err(|err| js_sys::Error::new(&err.to_string()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PeerAuth::Allowlist(keys))
+    }
+
+    /// Whether the peer with `remote_public_key` is allowed. A failing
+    /// callback denies the peer.
+    pub async fn authorize(&self, remote_public_key: &[u8]) -> bool {
+        match self {
+            PeerAuth::Any => true,
+            PeerAuth::Allowlist(keys) => keys.iter().any(|key| key == remote_public_key),
+            PeerAuth::Callback(callback) => {
+                let key = JsValue::from(hex::encode(remote_public_key));
+                let result = match callback.call1(&JsValue::NULL, &key) {
+                    Ok(result) => result,
+                    Err(err) => {
+                        error!("peer authorization callback failed: {:?}", err);
+                        return false;
+                    }
+                };
+                let result = match result.dyn_into::<Promise>() {
+                    Ok(promise) => JsFuture::from(promise).await,
+                    Err(result) => Ok(result),
+                };
+                match result {
+                    Ok(allowed) => allowed.as_bool().unwrap_or(false),
+                    Err(err) => {
+                        error!("peer authorization callback failed: {:?}", err);
+                        false
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,310 @@
+//! A merged, causally ordered log over the feeds of several writers.
+//!
+//! Every writer appends to its own feed. Block 0 is a header of type
+//! `autobase-lite`, and every further block carries a value and a vector
+//! clock: for each other writer, how many blocks of its feed the author had
+//! seen when appending. A block is ordered after its predecessor in the
+//! same feed and after every block its clock covers.
+//!
+//! The view orders all locally available blocks by that relation. Among the
+//! blocks that could come next, the one with the smallest causal height
+//! goes first, ties broken by the writer's public key, so every peer with
+//! the same blocks computes the same order. Blocks arriving later may be
+//! ordered before entries already seen in an earlier view.
+
+use anyhow::{anyhow, bail, Result};
+use futures::lock::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use crate::header::FeedHeader;
+use crate::hypercore::{FeedStore, FeedWrapper};
+use crate::persistence::FeedStorage;
+use crate::proto::{read_field, write_bytes, write_uint};
+
+/// Header type of the writer feeds.
+const AUTOBASE_TYPE: &str = "autobase-lite";
+
+const ENTRY_CLOCK_FIELD: u64 = 1;
+const ENTRY_VALUE_FIELD: u64 = 2;
+const CLOCK_KEY_FIELD: u64 = 1;
+const CLOCK_LENGTH_FIELD: u64 = 2;
+
+/// A block of a writer feed.
+#[derive(Debug, Clone)]
+struct WriterBlock {
+    /// Blocks seen of the other writers, by public key.
+    clock: Vec<([u8; 32], u64)>,
+    value: Vec<u8>,
+}
+
+impl WriterBlock {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        for (key, length) in &self.clock {
+            let mut clock = vec![];
+            write_bytes(&mut clock, CLOCK_KEY_FIELD, key);
+            write_uint(&mut clock, CLOCK_LENGTH_FIELD, *length);
+            write_bytes(&mut bytes, ENTRY_CLOCK_FIELD, &clock);
+        }
+        write_bytes(&mut bytes, ENTRY_VALUE_FIELD, &self.value);
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let mut block = WriterBlock {
+            clock: vec![],
+            value: vec![],
+        };
+        while !bytes.is_empty() {
+            match read_field(&mut bytes)? {
+                (ENTRY_CLOCK_FIELD, value) => {
+                    let mut clock = value.as_bytes()?;
+                    let (mut key, mut length) = (None, 0);
+                    while !clock.is_empty() {
+                        match read_field(&mut clock)? {
+                            (CLOCK_KEY_FIELD, value) => key = Some(value.as_bytes()?),
+                            (CLOCK_LENGTH_FIELD, value) => length = value.as_u64()?,
+                            _ => {}
+                        }
+                    }
+                    let key = key
+                        .ok_or_else(|| anyhow!("Clock entry has no key"))?
+                        .try_into()
+                        .map_err(|_| anyhow!("Invalid writer key length"))?;
+                    block.clock.push((key, length));
+                }
+                (ENTRY_VALUE_FIELD, value) => block.value = value.as_bytes()?.to_vec(),
+                _ => {}
+            }
+        }
+        Ok(block)
+    }
+}
+
+/// An entry of the merged view.
+#[derive(Debug, Clone)]
+pub struct ViewEntry {
+    /// Public key of the writer feed.
+    pub writer: [u8; 32],
+    /// Index of the block in the writer feed.
+    pub seq: u64,
+    pub value: Vec<u8>,
+}
+
+/// The merged log of a local writer and the remote writers.
+#[derive(Debug, Clone)]
+pub struct Autobase {
+    local: FeedWrapper<FeedStorage>,
+    /// All writer feeds, the local one included.
+    writers: FeedStore,
+    /// The view so far, extended as blocks arrive.
+    state: Arc<Mutex<Linearized>>,
+    /// Held while appending, so that clocks cover the latest blocks.
+    writer: Arc<Mutex<()>>,
+}
+
+impl Autobase {
+    /// Merge the writable feed `local` with the feeds of `remotes`.
+    pub fn new(local: FeedWrapper<FeedStorage>, remotes: Vec<FeedWrapper<FeedStorage>>) -> Self {
+        let mut autobase = Autobase {
+            local: local.clone(),
+            writers: FeedStore::new(),
+            state: Arc::new(Mutex::new(Linearized::default())),
+            writer: Arc::new(Mutex::new(())),
+        };
+        autobase.add_writer(local);
+        for remote in remotes {
+            autobase.add_writer(remote);
+        }
+        autobase
+    }
+
+    pub fn local(&self) -> &FeedWrapper<FeedStorage> {
+        &self.local
+    }
+
+    /// The feeds of all writers. Replicating them with a key resolver adds
+    /// the feeds it opens as writers.
+    pub fn writers(&self) -> &FeedStore {
+        &self.writers
+    }
+
+    /// Add the feed of another writer. A feed that is already a writer is
+    /// ignored.
+    pub fn add_writer(&mut self, feed: FeedWrapper<FeedStorage>) {
+        if self.writers.get(&feed.discovery_key()).is_none() {
+            self.writers.add(feed);
+        }
+    }
+
+    /// Append `value` to the local feed, with a clock covering every block
+    /// in the current view.
+    pub async fn append(&self, value: Vec<u8>) -> Result<()> {
+        let _writer = self.writer.lock().await;
+        if self.local.len().await == 0 {
+            let header = FeedHeader {
+                content_type: AUTOBASE_TYPE.to_string(),
+                ..FeedHeader::default()
+            };
+            self.local.write_header(&header).await?;
+        }
+        let clock = {
+            let mut state = self.state.lock().await;
+            state.update(&self.writers).await?;
+            let local = self.local.key();
+            let mut clock: Vec<_> = state
+                .writers
+                .iter()
+                .filter(|(key, writer)| **key != local && writer.included > 1)
+                .map(|(key, writer)| (*key, writer.included))
+                .collect();
+            clock.sort();
+            clock
+        };
+        let block = WriterBlock { clock, value };
+        self.local.append(&block.encode()).await?;
+        Ok(())
+    }
+
+    /// The entries of all writers in causal order.
+    pub async fn view(&self) -> Result<Vec<ViewEntry>> {
+        let mut state = self.state.lock().await;
+        state.update(&self.writers).await?;
+        Ok(state.view.iter().map(|(_, entry)| entry.clone()).collect())
+    }
+}
+
+/// What is known of one writer feed.
+#[derive(Debug)]
+struct WriterState {
+    /// Blocks read so far, counting the header.
+    read: u64,
+    /// Blocks in the view, counting the header.
+    included: u64,
+    /// Blocks read but not in the view yet, waiting for their clocks.
+    pending: VecDeque<WriterBlock>,
+    /// The causal height of every included block, by index.
+    heights: Vec<u64>,
+}
+
+impl Default for WriterState {
+    fn default() -> Self {
+        WriterState {
+            read: 0,
+            included: 1,
+            pending: VecDeque::new(),
+            heights: vec![0],
+        }
+    }
+}
+
+/// The view of the blocks read so far.
+///
+/// A block becomes ready once its predecessor and everything its clock
+/// covers is in the view. Its height is one more than the highest of those
+/// blocks, and the view is sorted by height and key. A block that arrives
+/// late, e.g. from a writer that was offline, may get a lower height than
+/// entries already in the view, and is inserted before them instead of
+/// recomputing the view.
+#[derive(Debug, Default)]
+struct Linearized {
+    writers: HashMap<[u8; 32], WriterState>,
+    /// The entries with their heights.
+    view: Vec<(u64, ViewEntry)>,
+}
+
+impl Linearized {
+    /// Read the blocks appended since the last update and add the ones
+    /// that are ready to the view.
+    async fn update(&mut self, feeds: &FeedStore) -> Result<()> {
+        for feed in feeds.feeds() {
+            let writer = self.writers.entry(feed.key()).or_default();
+            writer.read = read_blocks(&feed, writer.read, &mut writer.pending).await?;
+        }
+        // Including a block may make blocks of other writers ready.
+        let mut keys: Vec<[u8; 32]> = self.writers.keys().copied().collect();
+        keys.sort();
+        let mut progress = true;
+        while progress {
+            progress = false;
+            for key in &keys {
+                while let Some(height) = self.ready_height(key) {
+                    self.include(key, height);
+                    progress = true;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The height of the next block of `key`, if it is ready.
+    ///
+    /// A block whose clock names a writer that is never added stays pending,
+    /// and so do all later blocks of its writer.
+    fn ready_height(&self, key: &[u8; 32]) -> Option<u64> {
+        let writer = &self.writers[key];
+        let block = writer.pending.front()?;
+        let mut height = *writer.heights.last()?;
+        for (dep, length) in &block.clock {
+            if dep == key || *length <= 1 {
+                continue;
+            }
+            // Wait for the blocks, or the writer, to be added.
+            let dep = self.writers.get(dep)?;
+            height = height.max(*dep.heights.get(*length as usize - 1)?);
+        }
+        Some(height + 1)
+    }
+
+    fn include(&mut self, key: &[u8; 32], height: u64) {
+        let writer = self.writers.get_mut(key).expect("writer is known");
+        let block = writer.pending.pop_front().expect("block is ready");
+        let seq = writer.included;
+        writer.included += 1;
+        writer.heights.push(height);
+        let at = self
+            .view
+            .partition_point(|(other, entry)| (*other, entry.writer) <= (height, *key));
+        let entry = ViewEntry {
+            writer: *key,
+            seq,
+            value: block.value,
+        };
+        self.view.insert(at, (height, entry));
+    }
+}
+
+/// Read the blocks of a writer feed from `from` on into `blocks`, up to the
+/// first one that is not stored locally. Returns the number of blocks read,
+/// counting the header.
+async fn read_blocks(
+    feed: &FeedWrapper<FeedStorage>,
+    from: u64,
+    blocks: &mut VecDeque<WriterBlock>,
+) -> Result<u64> {
+    if from == 0 {
+        let header = match feed.header(false, None).await? {
+            Some(header) => header,
+            None => return Ok(0),
+        };
+        if header.content_type != AUTOBASE_TYPE {
+            bail!(
+                "Writer {} is a {}, not an {}",
+                feed.public_key(),
+                header.content_type,
+                AUTOBASE_TYPE
+            );
+        }
+    }
+    let mut read = from.max(1);
+    while read < feed.len().await {
+        match feed.get(read, false, None).await? {
+            Some(bytes) => blocks.push_back(WriterBlock::decode(&bytes)?),
+            None => break,
+        }
+        read += 1;
+    }
+    Ok(read)
+}
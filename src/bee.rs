@@ -0,0 +1,892 @@
+//! A hyperbee-style B-tree index stored in the blocks of a feed.
+//!
+//! Block 0 is a header of type `hyperbee`. Every further block holds one
+//! put or deleted key, and the tree nodes that changed with it, encoded like
+//! hyperbee's `Node` and `YoloIndex` messages. A node lists the blocks of its
+//! keys and points to its children as a block and the position of the node
+//! in that block's index. The root is the first node in the index of the
+//! latest block.
+//!
+//! Only the blocks on the way to a key are read, so the tree can be used on
+//! a sparsely replicated feed.
+
+use anyhow::{anyhow, bail, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::lock::Mutex;
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+
+use crate::header::FeedHeader;
+use crate::hypercore::FeedWrapper;
+use crate::persistence::FeedStorage;
+use crate::proto::{read_field, read_varint, write_bytes, write_varint, Value};
+
+/// Header type of the feed.
+const BEE_TYPE: &str = "hyperbee";
+/// Nodes hold at most this many keys, and all but the root at least half.
+const MAX_KEYS: usize = 8;
+const MIN_KEYS: usize = MAX_KEYS / 2;
+
+const NODE_INDEX_FIELD: u64 = 1;
+const NODE_KEY_FIELD: u64 = 2;
+const NODE_VALUE_FIELD: u64 = 3;
+const INDEX_LEVELS_FIELD: u64 = 1;
+const LEVEL_KEYS_FIELD: u64 = 1;
+const LEVEL_CHILDREN_FIELD: u64 = 2;
+
+/// A tree node as stored in the index of a block.
+#[derive(Debug, Clone, Default)]
+struct Level {
+    /// The blocks holding the keys, in key order.
+    keys: Vec<u64>,
+    /// The children as block and position in its index.
+    children: Vec<(u64, u64)>,
+}
+
+/// A decoded block of the feed.
+#[derive(Debug, Clone)]
+struct Block {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    index: Vec<Level>,
+}
+
+impl Block {
+    fn encode(&self) -> Vec<u8> {
+        let mut index = vec![];
+        for level in &self.index {
+            let mut bytes = vec![];
+            if !level.keys.is_empty() {
+                write_packed(&mut bytes, LEVEL_KEYS_FIELD, level.keys.iter().copied());
+            }
+            if !level.children.is_empty() {
+                let children = level.children.iter().flat_map(|(seq, at)| vec![*seq, *at]);
+                write_packed(&mut bytes, LEVEL_CHILDREN_FIELD, children);
+            }
+            write_bytes(&mut index, INDEX_LEVELS_FIELD, &bytes);
+        }
+        let mut bytes = vec![];
+        write_bytes(&mut bytes, NODE_INDEX_FIELD, &index);
+        write_bytes(&mut bytes, NODE_KEY_FIELD, &self.key);
+        if let Some(value) = &self.value {
+            write_bytes(&mut bytes, NODE_VALUE_FIELD, value);
+        }
+        bytes
+    }
+
+    fn decode(seq: u64, mut bytes: &[u8]) -> Result<Self> {
+        let (mut key, mut value, mut index) = (None, None, vec![]);
+        while !bytes.is_empty() {
+            let (field, field_value) = read_field(&mut bytes)?;
+            match field {
+                NODE_INDEX_FIELD => index = decode_index(field_value.as_bytes()?)?,
+                NODE_KEY_FIELD => key = Some(field_value.as_bytes()?.to_vec()),
+                NODE_VALUE_FIELD => value = Some(field_value.as_bytes()?.to_vec()),
+                _ => {}
+            }
+        }
+        let key = key.ok_or_else(|| anyhow!("Block {} has no key", seq))?;
+        Ok(Block { key, value, index })
+    }
+}
+
+fn write_packed(bytes: &mut Vec<u8>, field: u64, values: impl Iterator<Item = u64>) {
+    let mut packed = vec![];
+    for value in values {
+        write_varint(&mut packed, value);
+    }
+    write_bytes(bytes, field, &packed);
+}
+
+/// Read a repeated varint field, which may be packed or not.
+fn read_repeated(value: Value, values: &mut Vec<u64>) -> Result<()> {
+    match value {
+        Value::Varint(value) => values.push(value),
+        Value::Bytes(mut packed) => {
+            while !packed.is_empty() {
+                values.push(read_varint(&mut packed)?);
+            }
+        }
+        Value::Fixed => bail!("Invalid tree index"),
+    }
+    Ok(())
+}
+
+fn decode_index(mut bytes: &[u8]) -> Result<Vec<Level>> {
+    let mut levels = vec![];
+    while !bytes.is_empty() {
+        let (field, value) = read_field(&mut bytes)?;
+        if field != INDEX_LEVELS_FIELD {
+            continue;
+        }
+        let mut level_bytes = value.as_bytes()?;
+        let (mut keys, mut children) = (vec![], vec![]);
+        while !level_bytes.is_empty() {
+            match read_field(&mut level_bytes)? {
+                (LEVEL_KEYS_FIELD, value) => read_repeated(value, &mut keys)?,
+                (LEVEL_CHILDREN_FIELD, value) => read_repeated(value, &mut children)?,
+                _ => {}
+            }
+        }
+        if children.len() % 2 != 0 {
+            bail!("Invalid tree index");
+        }
+        let children = children.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+        levels.push(Level { keys, children });
+    }
+    Ok(levels)
+}
+
+/// Bounds and direction of a range query. Every query is limited, as the
+/// entries are collected in memory; the next page starts after the last
+/// key returned.
+#[derive(Debug, Clone)]
+pub struct RangeOptions {
+    pub gt: Option<Vec<u8>>,
+    pub gte: Option<Vec<u8>>,
+    pub lt: Option<Vec<u8>>,
+    pub lte: Option<Vec<u8>>,
+    pub reverse: bool,
+    pub limit: usize,
+}
+
+impl RangeOptions {
+    /// Whether `key` is below, within or above the bounds.
+    fn position(&self, key: &[u8]) -> Ordering {
+        let below = |bound: &Option<Vec<u8>>, inclusive: bool| {
+            bound.as_ref().map_or(false, |bound| {
+                key < &bound[..] || (!inclusive && key == &bound[..])
+            })
+        };
+        let above = |bound: &Option<Vec<u8>>, inclusive: bool| {
+            bound.as_ref().map_or(false, |bound| {
+                key > &bound[..] || (!inclusive && key == &bound[..])
+            })
+        };
+        if below(&self.gt, false) || below(&self.gte, true) {
+            Ordering::Less
+        } else if above(&self.lt, false) || above(&self.lte, true) {
+            Ordering::Greater
+        } else {
+            Ordering::Equal
+        }
+    }
+}
+
+/// An entry of the index.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// The block the entry was written in.
+    pub seq: u64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+}
+
+/// A key-value index over the blocks of a feed.
+#[derive(Debug, Clone)]
+pub struct Bee {
+    feed: FeedWrapper<FeedStorage>,
+    /// Held while writing, so that every block is built on the latest tree.
+    writer: Arc<Mutex<()>>,
+}
+
+impl Bee {
+    /// Use `feed` as index. Fails if its header is stored and of another
+    /// type.
+    pub async fn open(feed: FeedWrapper<FeedStorage>) -> Result<Self> {
+        feed.validate_header(BEE_TYPE).await?;
+        Ok(Bee {
+            feed,
+            writer: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn feed(&self) -> &FeedWrapper<FeedStorage> {
+        &self.feed
+    }
+
+    /// The value of `key`, if it is in the index.
+    pub async fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let mut tree = Tree::new(&self.feed);
+        let mut node = match tree.root().await? {
+            Some(root) => root,
+            None => return Ok(None),
+        };
+        loop {
+            let (i, found) = tree.search(node, key).await?;
+            if found {
+                let seq = tree.nodes[node].keys[i];
+                let block = tree.block(seq).await?;
+                let value = block.value.clone().unwrap_or_default();
+                let key = key.to_vec();
+                return Ok(Some(Entry { seq, key, value }));
+            }
+            if tree.nodes[node].is_leaf() {
+                return Ok(None);
+            }
+            node = tree.child(node, i).await?;
+        }
+    }
+
+    /// Set `key` to `value`. The feed must be writable.
+    pub async fn put(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        let _writer = self.writer.lock().await;
+        if self.feed.len().await == 0 {
+            let header = FeedHeader {
+                content_type: BEE_TYPE.to_string(),
+                ..FeedHeader::default()
+            };
+            self.feed.write_header(&header).await?;
+        }
+        let mut tree = Tree::new(&self.feed);
+        let root = tree.root().await?;
+        // The block about to be written, so that its key can be compared.
+        let seq = tree.len;
+        tree.blocks.insert(
+            seq,
+            Block {
+                key: key.to_vec(),
+                value: Some(value.to_vec()),
+                index: vec![],
+            },
+        );
+        let root = match root {
+            Some(root) => root,
+            None => {
+                let root = tree.add_node(vec![seq], vec![]);
+                tree.root = Some(root);
+                root
+            }
+        };
+        let mut path = vec![];
+        let mut node = root;
+        loop {
+            tree.nodes[node].changed = true;
+            let (i, found) = tree.search(node, key).await?;
+            if found {
+                tree.nodes[node].keys[i] = seq;
+                break;
+            }
+            if tree.nodes[node].is_leaf() {
+                tree.nodes[node].keys.insert(i, seq);
+                tree.split(node, &path);
+                break;
+            }
+            path.push((node, i));
+            node = tree.child(node, i).await?;
+        }
+        tree.append(key, Some(value)).await
+    }
+
+    /// Remove `key`. Returns whether it was in the index. The feed must be
+    /// writable.
+    pub async fn del(&self, key: &[u8]) -> Result<bool> {
+        let _writer = self.writer.lock().await;
+        let mut tree = Tree::new(&self.feed);
+        let mut node = match tree.root().await? {
+            Some(root) => root,
+            None => return Ok(false),
+        };
+        let mut path = vec![];
+        let i = loop {
+            let (i, found) = tree.search(node, key).await?;
+            if found {
+                break i;
+            }
+            if tree.nodes[node].is_leaf() {
+                return Ok(false);
+            }
+            path.push((node, i));
+            node = tree.child(node, i).await?;
+        };
+        for (ancestor, _) in &path {
+            tree.nodes[*ancestor].changed = true;
+        }
+        tree.nodes[node].changed = true;
+        if tree.nodes[node].is_leaf() {
+            tree.nodes[node].keys.remove(i);
+        } else {
+            // Replace the key with its predecessor from the rightmost leaf
+            // of the left subtree.
+            let with = node;
+            path.push((node, i));
+            node = tree.child(node, i).await?;
+            loop {
+                tree.nodes[node].changed = true;
+                if tree.nodes[node].is_leaf() {
+                    break;
+                }
+                let last = tree.nodes[node].children.len() - 1;
+                path.push((node, last));
+                node = tree.child(node, last).await?;
+            }
+            let predecessor = tree.nodes[node]
+                .keys
+                .pop()
+                .ok_or_else(|| anyhow!("Invalid tree index: empty leaf"))?;
+            tree.nodes[with].keys[i] = predecessor;
+        }
+        tree.rebalance(node, &path).await?;
+        tree.append(key, None).await?;
+        Ok(true)
+    }
+
+    /// The entries within the bounds of `options`, in key order or reversed.
+    pub async fn range(&self, options: &RangeOptions) -> Result<Vec<Entry>> {
+        let mut tree = Tree::new(&self.feed);
+        let mut entries = vec![];
+        if options.limit == 0 {
+            return Ok(entries);
+        }
+        if let Some(root) = tree.root().await? {
+            tree.visit(root, options, &mut entries).await?;
+        }
+        Ok(entries)
+    }
+}
+
+/// Where a node points to a child.
+#[derive(Debug, Clone, Copy)]
+enum Pointer {
+    Stored { seq: u64, offset: u64 },
+    Loaded(usize),
+}
+
+/// A tree node loaded for an operation.
+#[derive(Debug, Clone)]
+struct TreeNode {
+    keys: Vec<u64>,
+    children: Vec<Pointer>,
+    /// Where the node is stored, if it was loaded.
+    origin: Option<(u64, u64)>,
+    /// Whether the node has to be written to the new block.
+    changed: bool,
+}
+
+impl TreeNode {
+    fn is_leaf(&self) -> bool {
+        self.children.is_empty()
+    }
+}
+
+/// The nodes and blocks read during one operation.
+struct Tree<'a> {
+    feed: &'a FeedWrapper<FeedStorage>,
+    len: u64,
+    nodes: Vec<TreeNode>,
+    root: Option<usize>,
+    blocks: HashMap<u64, Block>,
+}
+
+impl<'a> Tree<'a> {
+    fn new(feed: &'a FeedWrapper<FeedStorage>) -> Self {
+        Tree {
+            feed,
+            len: 0,
+            nodes: vec![],
+            root: None,
+            blocks: HashMap::new(),
+        }
+    }
+
+    async fn block(&mut self, seq: u64) -> Result<&Block> {
+        if !self.blocks.contains_key(&seq) {
+            let bytes = self
+                .feed
+                .get(seq, true, None)
+                .await?
+                .ok_or_else(|| anyhow!("Block {} is missing", seq))?;
+            self.blocks.insert(seq, Block::decode(seq, &bytes)?);
+        }
+        Ok(&self.blocks[&seq])
+    }
+
+    /// Load the root from the latest block.
+    async fn root(&mut self) -> Result<Option<usize>> {
+        self.len = self.feed.len().await.max(1);
+        if self.len == 1 {
+            return Ok(None);
+        }
+        let seq = self.len - 1;
+        if self.block(seq).await?.index.is_empty() {
+            return Ok(None);
+        }
+        let root = self.load(seq, 0).await?;
+        self.root = Some(root);
+        Ok(Some(root))
+    }
+
+    async fn load(&mut self, seq: u64, offset: u64) -> Result<usize> {
+        let level = self
+            .block(seq)
+            .await?
+            .index
+            .get(offset as usize)
+            .cloned()
+            .ok_or_else(|| anyhow!("Block {} has no tree node {}", seq, offset))?;
+        // Children are stored before their parent: in an earlier block, or
+        // later in the index of the same block. Checking this rules out
+        // cycles in a hostile index.
+        let valid = (level.children.is_empty() || level.children.len() == level.keys.len() + 1)
+            && level.keys.iter().all(|key| *key <= seq && *key > 0)
+            && level
+                .children
+                .iter()
+                .all(|child| child.0 < seq || (child.0 == seq && child.1 > offset));
+        if !valid {
+            bail!("Invalid tree node {} in block {}", offset, seq);
+        }
+        let children = level
+            .children
+            .iter()
+            .map(|(seq, offset)| Pointer::Stored {
+                seq: *seq,
+                offset: *offset,
+            })
+            .collect();
+        self.nodes.push(TreeNode {
+            keys: level.keys,
+            children,
+            origin: Some((seq, offset)),
+            changed: false,
+        });
+        Ok(self.nodes.len() - 1)
+    }
+
+    fn add_node(&mut self, keys: Vec<u64>, children: Vec<Pointer>) -> usize {
+        self.nodes.push(TreeNode {
+            keys,
+            children,
+            origin: None,
+            changed: true,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// The `i`th child of `node`, loaded if needed.
+    async fn child(&mut self, node: usize, i: usize) -> Result<usize> {
+        match self.nodes[node].children[i] {
+            Pointer::Loaded(child) => Ok(child),
+            Pointer::Stored { seq, offset } => {
+                let child = self.load(seq, offset).await?;
+                self.nodes[node].children[i] = Pointer::Loaded(child);
+                Ok(child)
+            }
+        }
+    }
+
+    /// Binary search `key` in the keys of `node`. Returns its position and
+    /// whether it was found, or the position to insert it at.
+    async fn search(&mut self, node: usize, key: &[u8]) -> Result<(usize, bool)> {
+        let (mut low, mut high) = (0, self.nodes[node].keys.len());
+        while low < high {
+            let mid = (low + high) / 2;
+            let seq = self.nodes[node].keys[mid];
+            match self.block(seq).await?.key[..].cmp(key) {
+                Ordering::Equal => return Ok((mid, true)),
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+            }
+        }
+        Ok((low, false))
+    }
+
+    /// Split `node` and its ancestors on `path` while they hold too many
+    /// keys. The median key moves up to the parent.
+    fn split(&mut self, mut node: usize, path: &[(usize, usize)]) {
+        let mut path = path.iter().rev();
+        while self.nodes[node].keys.len() > MAX_KEYS {
+            let median = self.nodes[node].keys.len() / 2;
+            let right_keys = self.nodes[node].keys.split_off(median + 1);
+            let key = self.nodes[node].keys.pop().expect("median exists");
+            let right_children = if self.nodes[node].is_leaf() {
+                vec![]
+            } else {
+                self.nodes[node].children.split_off(median + 1)
+            };
+            let right = self.add_node(right_keys, right_children);
+            match path.next() {
+                Some((parent, i)) => {
+                    self.nodes[*parent].keys.insert(*i, key);
+                    self.nodes[*parent]
+                        .children
+                        .insert(i + 1, Pointer::Loaded(right));
+                    node = *parent;
+                }
+                None => {
+                    let children = vec![Pointer::Loaded(node), Pointer::Loaded(right)];
+                    self.root = Some(self.add_node(vec![key], children));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Fill up `node` and its ancestors on `path` that hold too few keys,
+    /// from a sibling or by merging with one.
+    async fn rebalance(&mut self, mut node: usize, path: &[(usize, usize)]) -> Result<()> {
+        for (parent, i) in path.iter().rev().copied() {
+            if self.nodes[node].keys.len() >= MIN_KEYS {
+                break;
+            }
+            let left = match i {
+                0 => None,
+                _ => Some(self.child(parent, i - 1).await?),
+            };
+            let right = if i + 1 < self.nodes[parent].children.len() {
+                Some(self.child(parent, i + 1).await?)
+            } else {
+                None
+            };
+            match (left, right) {
+                (Some(left), _) if self.nodes[left].keys.len() > MIN_KEYS => {
+                    self.nodes[left].changed = true;
+                    let key = self.nodes[left].keys.pop().expect("sibling has keys");
+                    let key = std::mem::replace(&mut self.nodes[parent].keys[i - 1], key);
+                    self.nodes[node].keys.insert(0, key);
+                    if let Some(child) = self.nodes[left].children.pop() {
+                        self.nodes[node].children.insert(0, child);
+                    }
+                }
+                (_, Some(right)) if self.nodes[right].keys.len() > MIN_KEYS => {
+                    self.nodes[right].changed = true;
+                    let key = self.nodes[right].keys.remove(0);
+                    let key = std::mem::replace(&mut self.nodes[parent].keys[i], key);
+                    self.nodes[node].keys.push(key);
+                    if !self.nodes[right].is_leaf() {
+                        let child = self.nodes[right].children.remove(0);
+                        self.nodes[node].children.push(child);
+                    }
+                }
+                (Some(left), _) => self.merge(parent, i - 1, left, node),
+                (None, Some(right)) => self.merge(parent, i, node, right),
+                // Only the root has no siblings.
+                (None, None) => bail!("Invalid tree index: node without siblings"),
+            }
+            node = parent;
+        }
+        // A root without keys is replaced by its only child.
+        if let Some(root) = self.root {
+            if self.nodes[root].keys.is_empty() {
+                self.root = match self.nodes[root].children.first() {
+                    Some(_) => {
+                        let child = self.child(root, 0).await?;
+                        self.nodes[child].changed = true;
+                        Some(child)
+                    }
+                    None => None,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge `right` and the parent key at `i` between them into `left`.
+    fn merge(&mut self, parent: usize, i: usize, left: usize, right: usize) {
+        let key = self.nodes[parent].keys.remove(i);
+        self.nodes[parent].children.remove(i + 1);
+        let (keys, children) = {
+            let right = &mut self.nodes[right];
+            (
+                std::mem::take(&mut right.keys),
+                std::mem::take(&mut right.children),
+            )
+        };
+        let left = &mut self.nodes[left];
+        left.changed = true;
+        left.keys.push(key);
+        left.keys.extend(keys);
+        left.children.extend(children);
+    }
+
+    /// Append a block for `key` with the changed nodes as its index, the
+    /// root first.
+    async fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<()> {
+        let block_seq = self.len;
+        let mut index = vec![];
+        if let Some(root) = self.root {
+            let mut offsets = HashMap::new();
+            let mut queue = VecDeque::from(vec![root]);
+            let mut order = vec![];
+            while let Some(node) = queue.pop_front() {
+                offsets.insert(node, order.len() as u64);
+                order.push(node);
+                for child in &self.nodes[node].children {
+                    if let Pointer::Loaded(child) = child {
+                        if self.nodes[*child].changed {
+                            queue.push_back(*child);
+                        }
+                    }
+                }
+            }
+            for node in order {
+                let children = self.nodes[node]
+                    .children
+                    .iter()
+                    .map(|child| match child {
+                        Pointer::Stored { seq, offset } => (*seq, *offset),
+                        Pointer::Loaded(child) => match offsets.get(child) {
+                            Some(offset) => (block_seq, *offset),
+                            None => self.nodes[*child]
+                                .origin
+                                .expect("unchanged nodes are stored"),
+                        },
+                    })
+                    .collect();
+                let keys = self.nodes[node].keys.clone();
+                index.push(Level { keys, children });
+            }
+        }
+        let block = Block {
+            key: key.to_vec(),
+            value: value.map(<[u8]>::to_vec),
+            index,
+        };
+        let appended = self.feed.append(&block.encode()).await?;
+        if appended != block_seq {
+            bail!("Feed was appended to while writing the index");
+        }
+        Ok(())
+    }
+
+    /// Collect the entries of the subtree at `node` within the bounds, in
+    /// order. Returns whether the range is complete.
+    fn visit<'b>(
+        &'b mut self,
+        node: usize,
+        options: &'b RangeOptions,
+        entries: &'b mut Vec<Entry>,
+    ) -> LocalBoxFuture<'b, Result<bool>> {
+        async move {
+            let count = self.nodes[node].keys.len();
+            let is_leaf = self.nodes[node].is_leaf();
+            // Visit child `i` and key `i` in turn, or key `i - 1` and child
+            // `i` when reversed.
+            let steps: Vec<usize> = if options.reverse {
+                (0..=count).rev().collect()
+            } else {
+                (0..=count).collect()
+            };
+            for i in steps {
+                let key_at = if options.reverse {
+                    i.checked_sub(1)
+                } else {
+                    Some(i).filter(|i| *i < count)
+                };
+                let position = match key_at {
+                    Some(at) => {
+                        let seq = self.nodes[node].keys[at];
+                        Some(options.position(&self.block(seq).await?.key))
+                    }
+                    None => None,
+                };
+                // Skip a child whose keys are all out of the bounds.
+                let outside = if options.reverse {
+                    Ordering::Greater
+                } else {
+                    Ordering::Less
+                };
+                if !is_leaf && position != Some(outside) {
+                    let child = self.child(node, i).await?;
+                    if self.visit(child, options, entries).await? {
+                        return Ok(true);
+                    }
+                }
+                match (key_at, position) {
+                    (Some(at), Some(Ordering::Equal)) => {
+                        let seq = self.nodes[node].keys[at];
+                        let block = self.block(seq).await?;
+                        entries.push(Entry {
+                            seq,
+                            key: block.key.clone(),
+                            value: block.value.clone().unwrap_or_default(),
+                        });
+                        if options.limit == entries.len() {
+                            return Ok(true);
+                        }
+                    }
+                    (Some(_), Some(position)) if position != outside => return Ok(true),
+                    _ => {}
+                }
+            }
+            Ok(false)
+        }
+        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hyperbee's encoding of `put('a', '1')` as the first entry: a root
+    /// leaf holding block 1.
+    const FIRST_PUT: &[u8] = &[
+        0x0a, 0x05, 0x0a, 0x03, 0x0a, 0x01, 0x01, 0x12, 0x01, b'a', 0x1a, 0x01, b'1',
+    ];
+
+    /// A deletion of `b` in block 10, whose root keeps block 5 and points
+    /// to node 0 of block 9 and to its leaf at position 1.
+    const DEL_WITH_CHILDREN: &[u8] = &[
+        0x0a, 0x11, 0x0a, 0x09, 0x0a, 0x01, 0x05, 0x12, 0x04, 0x09, 0x00, 0x0a, 0x01, 0x0a, 0x04,
+        0x0a, 0x02, 0x07, 0x0a, 0x12, 0x01, b'b',
+    ];
+
+    #[test]
+    fn decode_put_fixture() {
+        let block = Block::decode(1, FIRST_PUT).unwrap();
+        assert_eq!(block.key, b"a");
+        assert_eq!(block.value.as_deref(), Some(&b"1"[..]));
+        assert_eq!(block.index.len(), 1);
+        assert_eq!(block.index[0].keys, vec![1]);
+        assert!(block.index[0].children.is_empty());
+        assert_eq!(block.encode(), FIRST_PUT);
+    }
+
+    #[test]
+    fn decode_children_fixture() {
+        let block = Block::decode(10, DEL_WITH_CHILDREN).unwrap();
+        assert_eq!(block.key, b"b");
+        assert_eq!(block.value, None);
+        assert_eq!(block.index[0].keys, vec![5]);
+        assert_eq!(block.index[0].children, vec![(9, 0), (10, 1)]);
+        assert_eq!(block.index[1].keys, vec![7, 10]);
+        assert_eq!(block.encode(), DEL_WITH_CHILDREN);
+    }
+
+    #[test]
+    fn decode_unpacked_keys() {
+        // A level with keys 5 and 7 as separate varint fields.
+        let index = [0x0a, 0x04, 0x08, 0x05, 0x08, 0x07];
+        let levels = decode_index(&index).unwrap();
+        assert_eq!(levels[0].keys, vec![5, 7]);
+    }
+
+    #[test]
+    fn reject_odd_children() {
+        let index = [0x0a, 0x06, 0x0a, 0x01, 0x05, 0x12, 0x01, 0x09];
+        assert!(decode_index(&index).is_err());
+    }
+
+    #[test]
+    fn range_position() {
+        let options = RangeOptions {
+            gt: Some(b"b".to_vec()),
+            gte: None,
+            lt: None,
+            lte: Some(b"d".to_vec()),
+            reverse: false,
+            limit: 10,
+        };
+        assert_eq!(options.position(b"a"), Ordering::Less);
+        assert_eq!(options.position(b"b"), Ordering::Less);
+        assert_eq!(options.position(b"c"), Ordering::Equal);
+        assert_eq!(options.position(b"d"), Ordering::Equal);
+        assert_eq!(options.position(b"e"), Ordering::Greater);
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod web_tests {
+    use super::*;
+    use crate::hypercore::open_writable_feed;
+    use wasm_bindgen_test::*;
+
+    const COUNT: usize = 60;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key-{:03}", i).into_bytes()
+    }
+
+    async fn bee() -> Bee {
+        let name = format!("test-bee-{}", js_sys::Math::random());
+        Bee::open(open_writable_feed(&name).await.unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn all() -> RangeOptions {
+        RangeOptions {
+            gt: None,
+            gte: None,
+            lt: None,
+            lte: None,
+            reverse: false,
+            limit: usize::MAX,
+        }
+    }
+
+    /// Inserting out of order splits leaves and the root.
+    #[wasm_bindgen_test]
+    async fn put_splits() {
+        let bee = bee().await;
+        for i in 0..COUNT {
+            let i = i * 37 % COUNT;
+            bee.put(&key(i), &i.to_be_bytes()).await.unwrap();
+        }
+        for i in 0..COUNT {
+            let entry = bee.get(&key(i)).await.unwrap().unwrap();
+            assert_eq!(entry.value, i.to_be_bytes());
+        }
+        let keys: Vec<_> = bee
+            .range(&all())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(keys, (0..COUNT).map(key).collect::<Vec<_>>());
+    }
+
+    /// Deleting borrows from siblings and merges nodes until the tree
+    /// shrinks.
+    #[wasm_bindgen_test]
+    async fn del_borrows_and_merges() {
+        let bee = bee().await;
+        for i in 0..COUNT {
+            bee.put(&key(i), b"value").await.unwrap();
+        }
+        for i in (0..COUNT).filter(|i| i % 3 != 0) {
+            assert!(bee.del(&key(i)).await.unwrap());
+        }
+        assert!(!bee.del(&key(1)).await.unwrap());
+        for i in 0..COUNT {
+            assert_eq!(bee.get(&key(i)).await.unwrap().is_some(), i % 3 == 0);
+        }
+        let keys: Vec<_> = bee
+            .range(&all())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(keys, (0..COUNT).step_by(3).map(key).collect::<Vec<_>>());
+        for i in (0..COUNT).step_by(3) {
+            assert!(bee.del(&key(i)).await.unwrap());
+        }
+        assert!(bee.range(&all()).await.unwrap().is_empty());
+    }
+
+    #[wasm_bindgen_test]
+    async fn range_pages() {
+        let bee = bee().await;
+        for i in 0..COUNT {
+            bee.put(&key(i), b"value").await.unwrap();
+        }
+        let options = RangeOptions {
+            lt: Some(key(50)),
+            reverse: true,
+            limit: 7,
+            ..all()
+        };
+        let page = bee.range(&options).await.unwrap();
+        let keys: Vec<_> = page.iter().map(|entry| entry.key.clone()).collect();
+        assert_eq!(keys, (43..50).rev().map(key).collect::<Vec<_>>());
+        let next = RangeOptions {
+            lt: Some(key(43)),
+            ..options
+        };
+        assert_eq!(bee.range(&next).await.unwrap()[0].key, key(42));
+    }
+}
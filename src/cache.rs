@@ -0,0 +1,301 @@
+use std::collections::{BTreeMap, HashMap};
+
+/// Dirty byte ranges that have been written but not yet flushed to the
+/// backend.
+///
+/// Ranges are keyed by their start offset. Overlapping and adjacent writes
+/// are coalesced, so the map never contains two ranges that touch.
+#[derive(Debug, Default)]
+pub struct WriteCache {
+    ranges: BTreeMap<u64, Vec<u8>>,
+    dirty_bytes: usize,
+    flush_scheduled: bool,
+}
+
+impl WriteCache {
+    /// Buffer a write, merging it with any dirty range it touches.
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let end = offset + data.len() as u64;
+
+        // Collect all ranges that overlap or are adjacent to the new one.
+        let touching: Vec<u64> = self
+            .ranges
+            .range(..=end)
+            .rev()
+            .take_while(|(start, bytes)| *start + bytes.len() as u64 >= offset)
+            .map(|(start, _)| *start)
+            .collect();
+
+        let mut merged_start = offset;
+        let mut merged_end = end;
+        for start in &touching {
+            let len = self.ranges[start].len() as u64;
+            merged_start = merged_start.min(*start);
+            merged_end = merged_end.max(start + len);
+        }
+
+        let mut merged = vec![0; (merged_end - merged_start) as usize];
+        for start in touching {
+            let bytes = self.ranges.remove(&start).unwrap();
+            self.dirty_bytes -= bytes.len();
+            let at = (start - merged_start) as usize;
+            merged[at..at + bytes.len()].copy_from_slice(&bytes);
+        }
+        let at = (offset - merged_start) as usize;
+        merged[at..at + data.len()].copy_from_slice(data);
+
+        self.dirty_bytes += merged.len();
+        self.ranges.insert(merged_start, merged);
+    }
+
+    /// Return the requested bytes if they are entirely held in the cache.
+    pub fn get(&self, offset: u64, length: u64) -> Option<Vec<u8>> {
+        let (start, bytes) = self.ranges.range(..=offset).next_back()?;
+        let at = offset - start;
+        if at + length > bytes.len() as u64 {
+            return None;
+        }
+        Some(bytes[at as usize..(at + length) as usize].to_vec())
+    }
+
+    /// Whether any dirty range intersects `offset..offset + length`.
+    pub fn overlaps(&self, offset: u64, length: u64) -> bool {
+        let end = offset + length;
+        self.ranges
+            .range(..end)
+            .next_back()
+            .map_or(false, |(start, bytes)| start + bytes.len() as u64 > offset)
+    }
+
+    /// Drop cached bytes at and beyond `length`.
+    pub fn truncate(&mut self, length: u64) {
+        let mut tail = self.ranges.split_off(&length);
+        self.dirty_bytes -= tail.values().map(|b| b.len()).sum::<usize>();
+        tail.clear();
+        if let Some((start, bytes)) = self.ranges.iter_mut().next_back() {
+            let end = start + bytes.len() as u64;
+            if end > length {
+                let keep = (length - start) as usize;
+                self.dirty_bytes -= bytes.len() - keep;
+                bytes.truncate(keep);
+            }
+        }
+    }
+
+    /// Take all dirty ranges out of the cache, leaving it empty.
+    pub fn take(&mut self) -> Vec<(u64, Vec<u8>)> {
+        self.dirty_bytes = 0;
+        std::mem::take(&mut self.ranges).into_iter().collect()
+    }
+
+    pub fn dirty_bytes(&self) -> usize {
+        self.dirty_bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Mark that a timed flush is pending. Returns false if one already was.
+    pub fn schedule_flush(&mut self) -> bool {
+        !std::mem::replace(&mut self.flush_scheduled, true)
+    }
+
+    pub fn flush_done(&mut self) {
+        self.flush_scheduled = false;
+    }
+}
+
+/// A least-recently-used cache of fixed size pages read from the backend.
+#[derive(Debug)]
+pub struct ReadCache {
+    page_size: u64,
+    capacity: usize,
+    pages: HashMap<u64, (u64, Vec<u8>)>,
+    recency: BTreeMap<u64, u64>,
+    tick: u64,
+}
+
+impl ReadCache {
+    pub fn new(page_size: u64, capacity: usize) -> Self {
+        Self {
+            page_size,
+            capacity,
+            pages: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    pub fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    /// Get a page if it is cached and holds at least `min_len` bytes.
+    pub fn get(&mut self, page: u64, min_len: usize) -> Option<&[u8]> {
+        self.tick += 1;
+        let tick = self.tick;
+        let (last_used, bytes) = self.pages.get_mut(&page)?;
+        if bytes.len() < min_len {
+            return None;
+        }
+        self.recency.remove(last_used);
+        self.recency.insert(tick, page);
+        *last_used = tick;
+        Some(&bytes[..])
+    }
+
+    /// Insert a page, evicting the least recently used one when full.
+    pub fn insert(&mut self, page: u64, bytes: Vec<u8>) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.invalidate_page(page);
+        if self.pages.len() >= self.capacity {
+            if let Some(oldest) = self.recency.keys().next().copied() {
+                let page = self.recency.remove(&oldest).unwrap();
+                self.pages.remove(&page);
+            }
+        }
+        self.tick += 1;
+        self.recency.insert(self.tick, page);
+        self.pages.insert(page, (self.tick, bytes));
+    }
+
+    /// Drop all pages that intersect `offset..offset + length`.
+    pub fn invalidate(&mut self, offset: u64, length: u64) {
+        if length == 0 || self.pages.is_empty() {
+            return;
+        }
+        let first = offset / self.page_size;
+        let last = (offset + length - 1) / self.page_size;
+        let stale: Vec<u64> = self
+            .pages
+            .keys()
+            .filter(|p| **p >= first && **p <= last)
+            .copied()
+            .collect();
+        for page in stale {
+            self.invalidate_page(page);
+        }
+    }
+
+    /// Drop all pages at or beyond byte offset `length`.
+    pub fn invalidate_from(&mut self, length: u64) {
+        let first = length / self.page_size;
+        let stale: Vec<u64> = self
+            .pages
+            .keys()
+            .filter(|p| **p >= first)
+            .copied()
+            .collect();
+        for page in stale {
+            self.invalidate_page(page);
+        }
+    }
+
+    fn invalidate_page(&mut self, page: u64) {
+        if let Some((last_used, _)) = self.pages.remove(&page) {
+            self.recency.remove(&last_used);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_merges_touching_ranges() {
+        let mut cache = WriteCache::default();
+        cache.write(0, b"abc");
+        cache.write(10, b"xyz");
+        cache.write(3, b"de");
+        assert_eq!(cache.dirty_bytes(), 8);
+        // Overlapping both ranges and the gap, later bytes win.
+        cache.write(4, b"EFGHIJ");
+        assert_eq!(cache.dirty_bytes(), 13);
+        assert_eq!(cache.take(), vec![(0, b"abcdEFGHIJxyz".to_vec())]);
+        assert!(cache.is_empty());
+        assert_eq!(cache.dirty_bytes(), 0);
+    }
+
+    #[test]
+    fn get_only_returns_fully_cached_bytes() {
+        let mut cache = WriteCache::default();
+        cache.write(4, b"hello");
+        cache.write(20, b"world");
+        assert_eq!(cache.get(5, 3), Some(b"ell".to_vec()));
+        assert_eq!(cache.get(20, 5), Some(b"world".to_vec()));
+        assert_eq!(cache.get(3, 2), None);
+        assert_eq!(cache.get(8, 2), None);
+        assert_eq!(cache.get(12, 1), None);
+        assert!(cache.overlaps(8, 2));
+        assert!(cache.overlaps(0, 5));
+        assert!(!cache.overlaps(9, 11));
+        assert!(!cache.overlaps(0, 4));
+    }
+
+    #[test]
+    fn truncate_drops_and_shortens_ranges() {
+        let mut cache = WriteCache::default();
+        cache.write(0, b"hello");
+        cache.write(10, b"world");
+        cache.truncate(3);
+        assert_eq!(cache.dirty_bytes(), 3);
+        assert_eq!(cache.take(), vec![(0, b"hel".to_vec())]);
+        cache.write(0, b"abc");
+        cache.truncate(0);
+        assert!(cache.is_empty());
+        assert_eq!(cache.dirty_bytes(), 0);
+    }
+
+    #[test]
+    fn schedule_flush_once() {
+        let mut cache = WriteCache::default();
+        assert!(cache.schedule_flush());
+        assert!(!cache.schedule_flush());
+        cache.flush_done();
+        assert!(cache.schedule_flush());
+    }
+
+    #[test]
+    fn read_cache_evicts_least_recently_used() {
+        let mut cache = ReadCache::new(4, 2);
+        cache.insert(0, b"aaaa".to_vec());
+        cache.insert(1, b"bbbb".to_vec());
+        assert_eq!(cache.get(0, 4), Some(&b"aaaa"[..]));
+        cache.insert(2, b"cc".to_vec());
+        assert_eq!(cache.get(1, 0), None);
+        assert_eq!(cache.get(0, 4), Some(&b"aaaa"[..]));
+        // A page shorter than needed, e.g. before the store grew, misses.
+        assert_eq!(cache.get(2, 4), None);
+        assert_eq!(cache.get(2, 2), Some(&b"cc"[..]));
+    }
+
+    #[test]
+    fn read_cache_invalidates_pages() {
+        let mut cache = ReadCache::new(4, 8);
+        for page in 0..4 {
+            cache.insert(page, vec![page as u8; 4]);
+        }
+        cache.invalidate(5, 4);
+        assert!(cache.get(0, 4).is_some());
+        assert!(cache.get(1, 4).is_none());
+        assert!(cache.get(2, 4).is_none());
+        assert!(cache.get(3, 4).is_some());
+        cache.invalidate_from(2);
+        assert!(cache.get(0, 4).is_none());
+        assert!(cache.get(3, 4).is_none());
+    }
+
+    #[test]
+    fn read_cache_without_capacity() {
+        let mut cache = ReadCache::new(4, 0);
+        cache.insert(0, b"aaaa".to_vec());
+        assert_eq!(cache.get(0, 0), None);
+    }
+}
@@ -0,0 +1,631 @@
+//! Download scheduling across all peers of a feed.
+//!
+//! The `Scheduler` is shared between the feed handle and the replication
+//! tasks of a feed. It holds the selections of blocks to download, a set of
+//! block ranges with a channel that is resolved once all of them are stored
+//! locally, and the blocks every connected peer announced.
+//!
+//! Each replication task asks the scheduler for the next blocks to request
+//! from its peer. Blocks in flight on one peer are not handed out to another
+//! one. Every peer has up to `MAX_IN_FLIGHT` requests pipelined, so faster
+//! peers, which answer sooner, naturally take a larger share of the download.
+//! When a peer drops, its blocks are handed out again.
+
+use anyhow::Result;
+use futures::channel::{mpsc, oneshot};
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Identifies a selection for cancelling it.
+pub type DownloadId = u64;
+
+/// Identifies a connected peer.
+pub type PeerId = u64;
+
+/// Weight of a new sample in the smoothed round trip time.
+const RTT_WEIGHT: f64 = 0.2;
+
+/// Number of requests sent to a peer without waiting for the data.
+pub const MAX_IN_FLIGHT: usize = 16;
+
+#[derive(Debug)]
+struct Selection {
+    id: DownloadId,
+    /// The sorted and merged ranges that still have missing blocks. The
+    /// start of every range is its first missing block.
+    ranges: Vec<Range<u64>>,
+    /// All selected blocks before this one are local or in flight.
+    next: u64,
+    done: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    wake: mpsc::UnboundedSender<()>,
+    /// The sorted and merged ranges of blocks the peer announced.
+    has: Vec<Range<u64>>,
+    /// The requested blocks and when they were requested.
+    in_flight: HashMap<u64, f64>,
+    /// Smoothed round trip time of requests in milliseconds.
+    rtt: Option<f64>,
+    /// Whether the peer answers requests, as announced with `Status`.
+    uploading: bool,
+    stats: PeerStats,
+}
+
+impl Peer {
+    fn has(&self, index: u64) -> bool {
+        self.has.iter().any(|r| r.contains(&index))
+    }
+
+    /// The index after the last announced block.
+    fn end(&self) -> u64 {
+        self.has.last().map_or(0, |r| r.end)
+    }
+}
+
+/// Replication statistics of a connected peer.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStats {
+    /// Identifies the peer, e.g. for sending extension messages.
+    pub id: PeerId,
+    /// The peer's public key from the protocol handshake.
+    pub remote_public_key: Vec<u8>,
+    /// Number of blocks up to the last one the peer announced.
+    pub remote_length: u64,
+    pub uploaded_blocks: u64,
+    pub uploaded_bytes: u64,
+    pub downloaded_blocks: u64,
+    pub downloaded_bytes: u64,
+    /// Smoothed round trip time of requests in milliseconds.
+    pub rtt: Option<f64>,
+    /// When the channel was opened, in milliseconds since the epoch.
+    pub connected_at: f64,
+}
+
+/// The selections and peers of one feed.
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    next_id: DownloadId,
+    selections: Vec<Selection>,
+    next_peer: PeerId,
+    peers: HashMap<PeerId, Peer>,
+    /// The peer each block in flight is requested from.
+    in_flight: HashMap<u64, PeerId>,
+    /// All blocks before this one are stored locally.
+    cursor: u64,
+    /// All blocks before this one are local or in flight.
+    next: u64,
+    /// The length of the feed after the last local append.
+    appended: u64,
+}
+
+impl Scheduler {
+    /// Select `ranges` for download. The receiver resolves once all blocks
+    /// are local, and fails with `Canceled` if the selection is cancelled.
+    pub fn add(&mut self, ranges: Vec<Range<u64>>) -> (DownloadId, oneshot::Receiver<Result<()>>) {
+        let (done, done_rx) = oneshot::channel();
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut merged = vec![];
+        for range in ranges {
+            insert_range(&mut merged, range);
+        }
+        self.selections.push(Selection {
+            id,
+            ranges: merged,
+            next: 0,
+            done,
+        });
+        self.wake_peers();
+        (id, done_rx)
+    }
+
+    /// Drop a selection. Returns whether it was still pending.
+    pub fn cancel(&mut self, id: DownloadId) -> bool {
+        let len = self.selections.len();
+        self.selections.retain(|s| s.id != id);
+        self.selections.len() != len
+    }
+
+    /// Register a replication task connected at `now`. The receiver gets a
+    /// message whenever there may be new blocks for the peer to download.
+    pub fn add_peer(
+        &mut self,
+        remote_public_key: Vec<u8>,
+        now: f64,
+    ) -> (PeerId, mpsc::UnboundedReceiver<()>) {
+        let (wake, wake_rx) = mpsc::unbounded();
+        let id = self.next_peer;
+        self.next_peer += 1;
+        let peer = Peer {
+            wake,
+            has: vec![],
+            in_flight: HashMap::new(),
+            rtt: None,
+            uploading: true,
+            stats: PeerStats {
+                id,
+                remote_public_key,
+                connected_at: now,
+                ..Default::default()
+            },
+        };
+        self.peers.insert(id, peer);
+        (id, wake_rx)
+    }
+
+    /// Forget a disconnected peer and hand out its requested blocks again.
+    pub fn remove_peer(&mut self, id: PeerId) {
+        if let Some(peer) = self.peers.remove(&id) {
+            if !peer.in_flight.is_empty() {
+                self.requeue(peer.in_flight.keys().copied());
+            }
+        }
+    }
+
+    /// Hand out blocks that were in flight again.
+    fn requeue(&mut self, blocks: impl Iterator<Item = u64>) {
+        for index in blocks {
+            self.in_flight.remove(&index);
+            self.next = self.next.min(index);
+            for selection in self.selections.iter_mut() {
+                selection.next = selection.next.min(index);
+            }
+        }
+        self.wake_peers();
+    }
+
+    /// Record that blocks were appended locally, so that the feed has
+    /// `length` blocks. Every replication task is woken to announce them to
+    /// its peer.
+    pub fn on_append(&mut self, length: u64) {
+        self.appended = self.appended.max(length);
+        self.wake_peers();
+    }
+
+    /// The length of the feed after the last local append.
+    pub fn appended(&self) -> u64 {
+        self.appended
+    }
+
+    fn wake_peers(&mut self) {
+        for peer in self.peers.values() {
+            peer.wake.unbounded_send(()).ok();
+        }
+    }
+
+    /// Record that a peer has the blocks `range`. Returns the new end of its
+    /// announced blocks if the range extends them.
+    pub fn on_have(&mut self, id: PeerId, range: Range<u64>) -> Option<u64> {
+        let peer = self.peers.get_mut(&id)?;
+        let end = peer.end();
+        insert_range(&mut peer.has, range);
+        Some(peer.end()).filter(|new_end| *new_end > end)
+    }
+
+    /// Record that a peer no longer has the blocks `range`. Blocks in flight
+    /// on the peer in the range are handed out again.
+    pub fn on_unhave(&mut self, id: PeerId, range: Range<u64>) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        remove_range(&mut peer.has, range.clone());
+        let dropped: Vec<u64> = peer
+            .in_flight
+            .keys()
+            .copied()
+            .filter(|index| range.contains(index))
+            .collect();
+        if !dropped.is_empty() {
+            for index in dropped.iter() {
+                peer.in_flight.remove(index);
+            }
+            self.requeue(dropped.into_iter());
+        }
+    }
+
+    /// Record whether a peer answers requests. The blocks in flight on the
+    /// peer are handed out again if it stopped uploading.
+    pub fn set_uploading(&mut self, id: PeerId, uploading: bool) {
+        let peer = match self.peers.get_mut(&id) {
+            Some(peer) => peer,
+            None => return,
+        };
+        peer.uploading = uploading;
+        if !uploading && !peer.in_flight.is_empty() {
+            let dropped: Vec<u64> = peer.in_flight.drain().map(|(index, _)| index).collect();
+            self.requeue(dropped.into_iter());
+        }
+    }
+
+    /// The last block the peer announced.
+    pub fn remote_head(&self, id: PeerId) -> Option<u64> {
+        self.peers.get(&id)?.end().checked_sub(1)
+    }
+
+    /// Record that a block of `bytes` was sent to a peer.
+    pub fn on_upload(&mut self, id: PeerId, bytes: u64) {
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.stats.uploaded_blocks += 1;
+            peer.stats.uploaded_bytes += bytes;
+        }
+    }
+
+    /// Statistics of all connected peers.
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peers
+            .values()
+            .map(|peer| PeerStats {
+                remote_length: peer.end(),
+                rtt: peer.rtt,
+                ..peer.stats.clone()
+            })
+            .collect()
+    }
+
+    /// Record that a block of `bytes` arrived from a peer at `now`. Returns
+    /// whether the block was requested from the peer.
+    pub fn on_data(&mut self, id: PeerId, index: u64, bytes: u64, now: f64) -> bool {
+        if self.in_flight.get(&index) == Some(&id) {
+            self.in_flight.remove(&index);
+        }
+        let mut requested = false;
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.stats.downloaded_blocks += 1;
+            peer.stats.downloaded_bytes += bytes;
+            if let Some(sent) = peer.in_flight.remove(&index) {
+                let sample = now - sent;
+                peer.rtt = Some(match peer.rtt {
+                    Some(rtt) => rtt + RTT_WEIGHT * (sample - rtt),
+                    None => sample,
+                });
+                requested = true;
+            }
+        }
+        // Peers waiting for this block to finish their sync can go on.
+        self.wake_peers();
+        requested
+    }
+
+    /// Skip the blocks for which `has` is true and resolve the selections
+    /// that have no missing blocks left.
+    pub fn update(&mut self, has: &mut impl FnMut(u64) -> bool) {
+        let end = self.peers.values().map(|p| p.end()).max().unwrap_or(0);
+        while self.cursor < end && has(self.cursor) {
+            self.cursor += 1;
+        }
+        for selection in self.selections.iter_mut() {
+            for range in selection.ranges.iter_mut() {
+                while range.start < range.end && has(range.start) {
+                    range.start += 1;
+                }
+            }
+            selection.ranges.retain(|r| r.start < r.end);
+        }
+        let (done, pending) = self.selections.drain(..).partition(|s| s.ranges.is_empty());
+        self.selections = pending;
+        for selection in done {
+            selection.done.send(Ok(())).ok();
+        }
+    }
+
+    /// Pick the next block to request from a peer at `now` and mark it in
+    /// flight, as long as fewer than `MAX_IN_FLIGHT` are.
+    ///
+    /// Selected blocks go first, unless another uploading peer with a free
+    /// slot and a shorter round trip time has them too. Then, unless
+    /// `sparse` is set, the first missing block of the whole feed is picked.
+    pub fn next_for(
+        &mut self,
+        id: PeerId,
+        sparse: bool,
+        now: f64,
+        has: &mut impl FnMut(u64) -> bool,
+    ) -> Option<u64> {
+        let peer = self.peers.get(&id)?;
+        if peer.in_flight.len() >= MAX_IN_FLIGHT || !peer.uploading {
+            return None;
+        }
+        let mut next = None;
+        for selection in self.selections.iter_mut() {
+            let candidate = first_available(
+                &peer.has,
+                &self.in_flight,
+                &selection.ranges,
+                &mut selection.next,
+                has,
+            );
+            if let Some(index) = candidate {
+                if !faster_peer_has(&self.peers, id, index)
+                    && next.map_or(true, |next| index < next)
+                {
+                    next = Some(index);
+                }
+            }
+        }
+        if next.is_none() && !sparse {
+            let all = [self.cursor..u64::MAX];
+            next = first_available(&peer.has, &self.in_flight, &all, &mut self.next, has);
+        }
+        let index = next?;
+        self.in_flight.insert(index, id);
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.in_flight.insert(index, now);
+        }
+        Some(index)
+    }
+
+    /// Whether all blocks the peer has are local, apart from blocks that
+    /// were not selected in sparse mode.
+    pub fn is_synced(&self, id: PeerId, sparse: bool) -> bool {
+        let peer = match self.peers.get(&id) {
+            Some(peer) => peer,
+            None => return false,
+        };
+        if sparse {
+            let head = peer.end();
+            !self
+                .selections
+                .iter()
+                .flat_map(|s| s.ranges.iter())
+                .any(|r| r.start < head)
+        } else {
+            self.cursor >= peer.end()
+        }
+    }
+}
+
+/// The first block in the sorted `ranges` that is in `announced`, and
+/// neither local nor in flight.
+///
+/// `next` skips the blocks before it. It is moved past the blocks at its
+/// start that are local or in flight, so that they are not scanned again.
+fn first_available(
+    announced: &[Range<u64>],
+    in_flight: &HashMap<u64, PeerId>,
+    ranges: &[Range<u64>],
+    next: &mut u64,
+    has: &mut impl FnMut(u64) -> bool,
+) -> Option<u64> {
+    let mut taken = |index: u64| has(index) || in_flight.contains_key(&index);
+    for range in ranges.iter() {
+        if range.end <= *next {
+            continue;
+        }
+        *next = (*next).max(range.start);
+        while *next < range.end && taken(*next) {
+            *next += 1;
+        }
+        if *next < range.end {
+            break;
+        }
+    }
+    for range in ranges.iter().filter(|r| r.end > *next) {
+        for peer_range in announced.iter() {
+            let start = range.start.max(peer_range.start).max(*next);
+            let end = range.end.min(peer_range.end);
+            if let Some(index) = (start..end).find(|index| !taken(*index)) {
+                return Some(index);
+            }
+        }
+    }
+    None
+}
+
+/// Whether another uploading peer with a free slot and a shorter round trip
+/// time has the block.
+fn faster_peer_has(peers: &HashMap<PeerId, Peer>, id: PeerId, index: u64) -> bool {
+    let rtt = match peers.get(&id).and_then(|p| p.rtt) {
+        Some(rtt) => rtt,
+        None => return false,
+    };
+    peers.iter().any(|(other_id, other)| {
+        *other_id != id
+            && other.uploading
+            && other.in_flight.len() < MAX_IN_FLIGHT
+            && other.rtt.map_or(false, |other_rtt| other_rtt < rtt)
+            && other.has(index)
+    })
+}
+
+/// Insert `range` into the sorted, merged `ranges`.
+fn insert_range(ranges: &mut Vec<Range<u64>>, mut range: Range<u64>) {
+    if range.start >= range.end {
+        return;
+    }
+    let mut merged = Vec::with_capacity(ranges.len() + 1);
+    for existing in ranges.drain(..) {
+        if existing.end < range.start || existing.start > range.end {
+            merged.push(existing);
+        } else {
+            range.start = range.start.min(existing.start);
+            range.end = range.end.max(existing.end);
+        }
+    }
+    merged.push(range);
+    merged.sort_by_key(|r| r.start);
+    *ranges = merged;
+}
+
+/// Remove `range` from the sorted, merged `ranges`.
+fn remove_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    let mut rest = Vec::with_capacity(ranges.len() + 1);
+    for existing in ranges.drain(..) {
+        if existing.end <= range.start || existing.start >= range.end {
+            rest.push(existing);
+            continue;
+        }
+        if existing.start < range.start {
+            rest.push(existing.start..range.start);
+        }
+        if existing.end > range.end {
+            rest.push(range.end..existing.end);
+        }
+    }
+    *ranges = rest;
+}
+
+/// Merge a list of block indices into ranges.
+pub fn ranges_from_indices(mut indices: Vec<u64>) -> Vec<Range<u64>> {
+    indices.sort_unstable();
+    indices.dedup();
+    let mut ranges: Vec<Range<u64>> = vec![];
+    for index in indices {
+        match ranges.last_mut() {
+            Some(last) if last.end == index => last.end += 1,
+            _ => ranges.push(index..index + 1),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_with(scheduler: &mut Scheduler, has: Range<u64>) -> PeerId {
+        let (id, _wake) = scheduler.add_peer(vec![], 0.0);
+        scheduler.on_have(id, has);
+        id
+    }
+
+    #[test]
+    fn insert_merges_touching_ranges() {
+        let mut ranges = vec![];
+        insert_range(&mut ranges, 10..12);
+        insert_range(&mut ranges, 0..2);
+        insert_range(&mut ranges, 5..5);
+        assert_eq!(ranges, vec![0..2, 10..12]);
+        insert_range(&mut ranges, 2..4);
+        insert_range(&mut ranges, 8..10);
+        assert_eq!(ranges, vec![0..4, 8..12]);
+        insert_range(&mut ranges, 3..9);
+        assert_eq!(ranges, vec![0..12]);
+    }
+
+    #[test]
+    fn remove_splits_ranges() {
+        let mut ranges = vec![0..4, 8..12];
+        remove_range(&mut ranges, 2..9);
+        assert_eq!(ranges, vec![0..2, 9..12]);
+        remove_range(&mut ranges, 10..11);
+        assert_eq!(ranges, vec![0..2, 9..10, 11..12]);
+        remove_range(&mut ranges, 0..20);
+        assert!(ranges.is_empty());
+    }
+
+    #[test]
+    fn merge_indices() {
+        assert_eq!(
+            ranges_from_indices(vec![5, 1, 2, 2, 0, 7, 6]),
+            vec![0..3, 5..8]
+        );
+        assert!(ranges_from_indices(vec![]).is_empty());
+    }
+
+    #[test]
+    fn pipeline_requests() {
+        let mut scheduler = Scheduler::default();
+        let peer = peer_with(&mut scheduler, 0..100);
+        let mut has = |index: u64| index < 2;
+        let requested: Vec<u64> =
+            std::iter::from_fn(|| scheduler.next_for(peer, false, 0.0, &mut has)).collect();
+        assert_eq!(requested, (2..2 + MAX_IN_FLIGHT as u64).collect::<Vec<_>>());
+        // A free slot is filled with the next block.
+        assert!(scheduler.on_data(peer, 2, 10, 5.0));
+        assert_eq!(scheduler.next_for(peer, false, 5.0, &mut has), Some(18));
+        assert!(!scheduler.on_data(peer, 50, 10, 5.0));
+    }
+
+    #[test]
+    fn split_blocks_between_peers() {
+        let mut scheduler = Scheduler::default();
+        let first = peer_with(&mut scheduler, 0..4);
+        let second = peer_with(&mut scheduler, 0..4);
+        let mut has = |_| false;
+        assert_eq!(scheduler.next_for(first, false, 0.0, &mut has), Some(0));
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), Some(1));
+        assert_eq!(scheduler.next_for(first, false, 0.0, &mut has), Some(2));
+        // A dropped peer's blocks are handed out again.
+        scheduler.remove_peer(first);
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), Some(0));
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), Some(2));
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), Some(3));
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), None);
+    }
+
+    #[test]
+    fn requeue_unhave_and_stopped_upload() {
+        let mut scheduler = Scheduler::default();
+        let first = peer_with(&mut scheduler, 0..4);
+        let second = peer_with(&mut scheduler, 0..4);
+        let mut has = |_| false;
+        assert_eq!(scheduler.next_for(first, false, 0.0, &mut has), Some(0));
+        assert_eq!(scheduler.next_for(first, false, 0.0, &mut has), Some(1));
+        scheduler.on_unhave(first, 1..2);
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), Some(1));
+        scheduler.set_uploading(first, false);
+        assert_eq!(scheduler.next_for(first, false, 0.0, &mut has), None);
+        assert_eq!(scheduler.next_for(second, false, 0.0, &mut has), Some(0));
+    }
+
+    #[test]
+    fn selections_go_first() {
+        let mut scheduler = Scheduler::default();
+        let peer = peer_with(&mut scheduler, 0..100);
+        let (_, _done) = scheduler.add(vec![60..62, 40..41]);
+        let mut has = |_| false;
+        assert_eq!(scheduler.next_for(peer, true, 0.0, &mut has), Some(40));
+        assert_eq!(scheduler.next_for(peer, true, 0.0, &mut has), Some(60));
+        assert_eq!(scheduler.next_for(peer, true, 0.0, &mut has), Some(61));
+        assert_eq!(scheduler.next_for(peer, true, 0.0, &mut has), None);
+        assert_eq!(scheduler.next_for(peer, false, 0.0, &mut has), Some(0));
+    }
+
+    #[test]
+    fn leave_blocks_to_faster_peers() {
+        let mut scheduler = Scheduler::default();
+        let slow = peer_with(&mut scheduler, 0..10);
+        let fast = peer_with(&mut scheduler, 0..10);
+        let mut has = |_| false;
+        for (peer, rtt) in [(slow, 100.0), (fast, 10.0)].iter() {
+            let index = scheduler.next_for(*peer, false, 0.0, &mut has).unwrap();
+            scheduler.on_data(*peer, index, 1, *rtt);
+        }
+        let (_, _done) = scheduler.add(vec![5..6]);
+        assert_eq!(scheduler.next_for(slow, true, 0.0, &mut has), None);
+        // A faster peer that doesn't upload doesn't hold the block back.
+        scheduler.set_uploading(fast, false);
+        assert_eq!(scheduler.next_for(slow, true, 0.0, &mut has), Some(5));
+    }
+
+    #[test]
+    fn wake_peers_on_append() {
+        let mut scheduler = Scheduler::default();
+        let (_, mut first) = scheduler.add_peer(vec![], 0.0);
+        let (_, mut second) = scheduler.add_peer(vec![], 0.0);
+        scheduler.on_append(3);
+        scheduler.on_append(2);
+        assert_eq!(scheduler.appended(), 3);
+        assert_eq!(first.try_next().unwrap(), Some(()));
+        assert_eq!(second.try_next().unwrap(), Some(()));
+    }
+
+    #[test]
+    fn resolve_complete_selections() {
+        let mut scheduler = Scheduler::default();
+        let peer = peer_with(&mut scheduler, 0..4);
+        let (_, mut done) = scheduler.add(vec![1..3]);
+        scheduler.update(&mut |index| index == 1);
+        assert_eq!(done.try_recv().unwrap().map(|r| r.is_ok()), None);
+        assert!(!scheduler.is_synced(peer, true));
+        scheduler.update(&mut |index| index < 3);
+        assert_eq!(done.try_recv().unwrap().map(|r| r.is_ok()), Some(true));
+        assert!(scheduler.is_synced(peer, true));
+        assert!(!scheduler.is_synced(peer, false));
+        scheduler.update(&mut |_| true);
+        assert!(scheduler.is_synced(peer, false));
+    }
+}
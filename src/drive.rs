@@ -0,0 +1,681 @@
+//! A hyperdrive-style filesystem over a metadata and a content feed.
+//!
+//! The layout is the one of hyperdrive 10. The metadata feed is a
+//! hypertrie: block 0 is a header of type `hypertrie` carrying the public
+//! key of the content feed, and every further block is a trie node that maps
+//! a path to the `Stat` of a file. File contents are appended to the content
+//! feed in blocks of up to 64 KiB, and the `Stat` records which blocks hold
+//! them.
+//!
+//! Each trie node stores pointers to older nodes whose path hashes diverge
+//! from its own, so a lookup only downloads the nodes on the way to a path.
+//! Listing a directory reads all nodes.
+
+use anyhow::{anyhow, bail, Result};
+use futures::lock::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryInto;
+use std::hash::Hasher;
+use std::sync::Arc;
+
+use crate::hypercore::{open_feed_by_key, FeedWrapper};
+use crate::persistence::FeedStorage;
+use crate::proto::{read_field, read_varint, write_bytes, write_uint, write_varint};
+
+/// Header type of the metadata feed.
+const TRIE_TYPE: &str = "hypertrie";
+/// Header subtype hyperdrive sets on the metadata feed.
+const DRIVE_SUBTYPE: &str = "hyperdrive";
+/// Size of the content blocks a file is split into.
+const BLOCK_SIZE: usize = 64 * 1024;
+const FILE_MODE: u32 = 0o100644;
+const DIR_MODE: u32 = 0o40755;
+
+const NODE_KEY_FIELD: u64 = 1;
+const NODE_VALUE_FIELD: u64 = 2;
+const NODE_TRIE_FIELD: u64 = 3;
+const NODE_FLAGS_FIELD: u64 = 4;
+
+/// Nodes with this flag are hidden from the filesystem, e.g. mount points.
+const HIDDEN_FLAG: u64 = 1;
+/// The path value after the last hash of a key.
+const TERMINATOR: u8 = 4;
+/// Number of values a path element can take.
+const BUCKET_SIZE: usize = 5;
+
+/// File metadata, encoded like hyperdrive's `Stat` message.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stat {
+    pub mode: u32,
+    /// Size of the file in bytes.
+    pub size: u64,
+    /// Number of content blocks holding the file.
+    pub blocks: u64,
+    /// Index of the first content block.
+    pub offset: u64,
+    /// Byte offset of the first content block in the content feed.
+    pub byte_offset: u64,
+    /// Modification time in milliseconds since the epoch.
+    pub mtime: u64,
+    /// Creation time in milliseconds since the epoch.
+    pub ctime: u64,
+}
+
+impl Stat {
+    fn directory() -> Self {
+        Stat {
+            mode: DIR_MODE,
+            ..Stat::default()
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & 0o170000 == 0o040000
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_uint(&mut bytes, 1, u64::from(self.mode));
+        write_uint(&mut bytes, 4, self.size);
+        write_uint(&mut bytes, 5, self.blocks);
+        write_uint(&mut bytes, 6, self.offset);
+        write_uint(&mut bytes, 7, self.byte_offset);
+        write_uint(&mut bytes, 8, self.mtime);
+        write_uint(&mut bytes, 9, self.ctime);
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let mut stat = Stat::default();
+        while !bytes.is_empty() {
+            let (field, value) = read_field(&mut bytes)?;
+            match field {
+                1 => stat.mode = value.as_u64()? as u32,
+                4 => stat.size = value.as_u64()?,
+                5 => stat.blocks = value.as_u64()?,
+                6 => stat.offset = value.as_u64()?,
+                7 => stat.byte_offset = value.as_u64()?,
+                8 => stat.mtime = value.as_u64()?,
+                9 => stat.ctime = value.as_u64()?,
+                // Owner, link name, mount and custom metadata are not used.
+                _ => {}
+            }
+        }
+        Ok(stat)
+    }
+}
+
+/// Errors of filesystem operations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DriveError {
+    NotFound { path: String },
+    IsDirectory { path: String },
+}
+
+impl DriveError {
+    /// The name of the error as exposed to JavaScript.
+    pub fn name(&self) -> &str {
+        match self {
+            DriveError::NotFound { .. } => "NotFoundError",
+            DriveError::IsDirectory { .. } => "IsDirectoryError",
+        }
+    }
+}
+
+impl std::fmt::Display for DriveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriveError::NotFound { path } => write!(f, "No such file or directory: {}", path),
+            DriveError::IsDirectory { path } => write!(f, "Is a directory: {}", path),
+        }
+    }
+}
+
+impl std::error::Error for DriveError {}
+
+impl From<DriveError> for wasm_bindgen::JsValue {
+    fn from(err: DriveError) -> Self {
+        let js_err = js_sys::Error::new(&err.to_string());
+        js_err.set_name(err.name());
+        js_err.into()
+    }
+}
+
+/// A hypertrie node, stored as one block of the metadata feed.
+#[derive(Debug, Clone)]
+struct TrieNode {
+    seq: u64,
+    key: String,
+    value: Option<Vec<u8>>,
+    flags: u64,
+    /// Per path element, the newest older node for each diverging value.
+    /// Zero means none, as block 0 is the header.
+    trie: BTreeMap<usize, [u64; BUCKET_SIZE]>,
+    hash: Vec<u8>,
+}
+
+impl TrieNode {
+    fn new(seq: u64, key: String, value: Option<Vec<u8>>, flags: u64) -> Self {
+        let hash = hash_key(&key);
+        TrieNode {
+            seq,
+            key,
+            value,
+            flags,
+            trie: BTreeMap::new(),
+            hash,
+        }
+    }
+
+    fn decode(seq: u64, mut bytes: &[u8]) -> Result<Self> {
+        let (mut key, mut value, mut trie, mut flags) = (None, None, BTreeMap::new(), 0);
+        while !bytes.is_empty() {
+            let (field, field_value) = read_field(&mut bytes)?;
+            match field {
+                NODE_KEY_FIELD => key = Some(field_value.as_string()?),
+                NODE_VALUE_FIELD => value = Some(field_value.as_bytes()?.to_vec()),
+                NODE_TRIE_FIELD => trie = decode_trie(field_value.as_bytes()?)?,
+                NODE_FLAGS_FIELD => flags = field_value.as_u64()?,
+                _ => {}
+            }
+        }
+        let key = key.ok_or_else(|| anyhow!("Trie node {} has no key", seq))?;
+        let mut node = TrieNode::new(seq, key, value, flags);
+        node.trie = trie;
+        Ok(node)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_bytes(&mut bytes, NODE_KEY_FIELD, self.key.as_bytes());
+        if let Some(value) = &self.value {
+            write_bytes(&mut bytes, NODE_VALUE_FIELD, value);
+        }
+        write_bytes(&mut bytes, NODE_TRIE_FIELD, &encode_trie(&self.trie));
+        if self.flags != 0 {
+            write_uint(&mut bytes, NODE_FLAGS_FIELD, self.flags);
+        }
+        bytes
+    }
+
+    /// Number of path elements: the hidden flag, four per hash byte and
+    /// the terminator.
+    fn path_len(&self) -> usize {
+        self.hash.len() * 4 + 2
+    }
+
+    /// The path element at `i`.
+    fn path(&self, i: usize) -> u8 {
+        if i == 0 {
+            return (self.flags & HIDDEN_FLAG) as u8;
+        }
+        let i = i - 1;
+        match self.hash.get(i / 4) {
+            Some(byte) => (byte >> (2 * (i % 4))) & 3,
+            None => TERMINATOR,
+        }
+    }
+
+    /// The node pointed to for path element `i` taking `value`, or 0.
+    fn pointer(&self, i: usize, value: u8) -> u64 {
+        self.trie
+            .get(&i)
+            .map_or(0, |bucket| bucket[usize::from(value)])
+    }
+}
+
+/// Hash every component of a key with SipHash-2-4 and a zero key, as
+/// libsodium's `crypto_shorthash` does.
+fn hash_key(key: &str) -> Vec<u8> {
+    let mut hash = Vec::with_capacity(8);
+    for component in key.split('/') {
+        #[allow(deprecated)]
+        let mut hasher = std::hash::SipHasher::new_with_keys(0, 0);
+        hasher.write(component.as_bytes());
+        hash.extend_from_slice(&hasher.finish().to_le_bytes());
+    }
+    hash
+}
+
+/// Encode the trie pointers: for every non-empty element its index, a
+/// bitfield of the values set and the pointers of these values.
+fn encode_trie(trie: &BTreeMap<usize, [u64; BUCKET_SIZE]>) -> Vec<u8> {
+    let mut bytes = vec![];
+    for (i, bucket) in trie {
+        let bitfield = bucket
+            .iter()
+            .enumerate()
+            .filter(|(_, seq)| **seq != 0)
+            .fold(0, |bitfield, (value, _)| bitfield | 1 << value);
+        if bitfield == 0 {
+            continue;
+        }
+        write_varint(&mut bytes, *i as u64);
+        write_varint(&mut bytes, bitfield);
+        for seq in bucket.iter().filter(|seq| **seq != 0) {
+            write_varint(&mut bytes, *seq);
+        }
+    }
+    bytes
+}
+
+fn decode_trie(mut bytes: &[u8]) -> Result<BTreeMap<usize, [u64; BUCKET_SIZE]>> {
+    let mut trie = BTreeMap::new();
+    while !bytes.is_empty() {
+        let i = read_varint(&mut bytes)? as usize;
+        let bitfield = read_varint(&mut bytes)?;
+        if bitfield >> BUCKET_SIZE != 0 {
+            bail!("Invalid trie bucket {:b}", bitfield);
+        }
+        let mut bucket = [0; BUCKET_SIZE];
+        for (value, seq) in bucket.iter_mut().enumerate() {
+            if bitfield & 1 << value != 0 {
+                *seq = read_varint(&mut bytes)?;
+            }
+        }
+        trie.insert(i, bucket);
+    }
+    Ok(trie)
+}
+
+/// Paths are stored without leading and trailing slashes.
+fn normalize(path: &str) -> String {
+    path.trim_matches('/').to_string()
+}
+
+/// A filesystem stored in a hypertrie metadata feed and a content feed.
+#[derive(Debug, Clone)]
+pub struct Drive {
+    metadata: FeedWrapper<FeedStorage>,
+    content: FeedWrapper<FeedStorage>,
+    /// Held while writing, so that every new node is built on the latest.
+    writer: Arc<Mutex<()>>,
+}
+
+impl Drive {
+    /// Read the content feed key from the header of the metadata feed.
+    /// Returns `None` if the header is missing and `wait` is not set.
+    pub async fn content_key(
+        metadata: &FeedWrapper<FeedStorage>,
+        wait: bool,
+    ) -> Result<Option<[u8; 32]>> {
+        let header = match metadata.header(wait, None).await? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if header.content_type != TRIE_TYPE {
+            bail!("Feed is a {}, not a {}", header.content_type, TRIE_TYPE);
+        }
+        if header
+            .schema
+            .as_deref()
+            .map_or(false, |s| s != DRIVE_SUBTYPE)
+        {
+            bail!("Trie is not a {}", DRIVE_SUBTYPE);
+        }
+        let key = header
+            .metadata
+            .ok_or_else(|| anyhow!("Drive header has no content feed key"))?;
+        let key = key
+            .try_into()
+            .map_err(|_| anyhow!("Invalid content feed key length"))?;
+        Ok(Some(key))
+    }
+
+    /// Open the drive of a metadata feed along with its content feed.
+    /// Returns `None` if the header is missing and `wait` is not set.
+    pub async fn open(metadata: FeedWrapper<FeedStorage>, wait: bool) -> Result<Option<Self>> {
+        let key = match Self::content_key(&metadata, wait).await? {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+        let content = open_feed_by_key(key).await?;
+        Ok(Some(Drive {
+            metadata,
+            content,
+            writer: Arc::new(Mutex::new(())),
+        }))
+    }
+
+    pub fn metadata(&self) -> &FeedWrapper<FeedStorage> {
+        &self.metadata
+    }
+
+    pub fn content(&self) -> &FeedWrapper<FeedStorage> {
+        &self.content
+    }
+
+    /// The metadata of the file or directory at `path`. Directories are
+    /// not stored, a path is one if files exist below it.
+    pub async fn stat(&self, path: &str) -> Result<Stat> {
+        let key = normalize(path);
+        if key.is_empty() {
+            return Ok(Stat::directory());
+        }
+        if let Some(node) = self.lookup(&key).await? {
+            if let Some(value) = node.value {
+                return Stat::decode(&value);
+            }
+        }
+        if !self.readdir(&key).await?.is_empty() {
+            return Ok(Stat::directory());
+        }
+        Err(DriveError::NotFound {
+            path: path.to_string(),
+        }
+        .into())
+    }
+
+    /// The sorted names of the entries of the directory at `path`.
+    pub async fn readdir(&self, path: &str) -> Result<Vec<String>> {
+        let key = normalize(path);
+        let prefix = if key.is_empty() {
+            key
+        } else {
+            format!("{}/", key)
+        };
+        let names: BTreeSet<String> = self
+            .entries()
+            .await?
+            .keys()
+            .filter_map(|key| key.strip_prefix(&prefix))
+            .filter_map(|rest| rest.split('/').next())
+            .map(str::to_string)
+            .collect();
+        Ok(names.into_iter().collect())
+    }
+
+    /// The contents of the file at `path`. Missing content blocks are
+    /// downloaded.
+    pub async fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let stat = self.stat(path).await?;
+        if stat.is_dir() {
+            return Err(DriveError::IsDirectory {
+                path: path.to_string(),
+            }
+            .into());
+        }
+        let end = stat
+            .offset
+            .checked_add(stat.blocks)
+            .ok_or_else(|| anyhow!("Invalid block range of {}", path))?;
+        let blocks = stat.offset..end;
+        if !blocks.is_empty() {
+            let (_, done) = self.content.download(vec![blocks.clone()]).await;
+            done.await??;
+        }
+        // The stat comes from the remote, so its size isn't trusted for
+        // allocating up front.
+        let mut data = Vec::new();
+        for index in blocks {
+            let block = self
+                .content
+                .get(index, false, None)
+                .await?
+                .ok_or_else(|| anyhow!("Content block {} missing after download", index))?;
+            data.extend_from_slice(&block);
+        }
+        Ok(data)
+    }
+
+    /// Write `data` to the file at `path`, replacing an existing file. Both
+    /// feeds must be writable. `now` is the time in milliseconds since the
+    /// epoch.
+    pub async fn write_file(&self, path: &str, data: &[u8], now: u64) -> Result<Stat> {
+        let key = normalize(path);
+        if key.is_empty() {
+            return Err(DriveError::IsDirectory {
+                path: path.to_string(),
+            }
+            .into());
+        }
+        let _writer = self.writer.lock().await;
+        let stat = Stat {
+            mode: FILE_MODE,
+            size: data.len() as u64,
+            blocks: data.chunks(BLOCK_SIZE).count() as u64,
+            offset: self.content.len().await,
+            byte_offset: self.content.byte_len().await,
+            mtime: now,
+            ctime: now,
+        };
+        for block in data.chunks(BLOCK_SIZE) {
+            self.content.append(block).await?;
+        }
+        self.put(key, stat.encode()).await?;
+        Ok(stat)
+    }
+
+    async fn node(&self, seq: u64) -> Result<TrieNode> {
+        let block = self
+            .metadata
+            .get(seq, true, None)
+            .await?
+            .ok_or_else(|| anyhow!("Trie node {} is missing", seq))?;
+        TrieNode::decode(seq, &block)
+    }
+
+    async fn head(&self) -> Result<Option<TrieNode>> {
+        match self.metadata.len().await {
+            0 | 1 => Ok(None),
+            len => Ok(Some(self.node(len - 1).await?)),
+        }
+    }
+
+    /// Find the newest node for `key` by following the trie from the head.
+    async fn lookup(&self, key: &str) -> Result<Option<TrieNode>> {
+        let target = TrieNode::new(0, key.to_string(), None, 0);
+        let mut node = match self.head().await? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let mut i = 0;
+        while i < target.path_len() {
+            let value = target.path(i);
+            if node.path(i) != value {
+                match node.pointer(i, value) {
+                    0 => return Ok(None),
+                    // The node pointed to shares the path up to and including `i`.
+                    seq => node = self.node(seq).await?,
+                }
+            }
+            i += 1;
+        }
+        // Different keys only share all path elements on a hash collision.
+        Ok(Some(node).filter(|node| node.key == target.key))
+    }
+
+    /// Append a node for `key`. Its trie is taken over from the nodes on
+    /// the way to `key`, with pointers to where their paths diverge.
+    async fn put(&self, key: String, value: Vec<u8>) -> Result<()> {
+        let seq = self.metadata.len().await;
+        let mut node = TrieNode::new(seq, key, Some(value), 0);
+        let mut next = self.head().await?;
+        let mut i = 0;
+        while let Some(other) = next.take() {
+            if i >= node.path_len() {
+                break;
+            }
+            let value = node.path(i);
+            let mut bucket = other.trie.get(&i).copied().unwrap_or_default();
+            bucket[usize::from(value)] = 0;
+            let other_value = other.path(i);
+            if other_value == value {
+                next = Some(other);
+            } else {
+                bucket[usize::from(other_value)] = other.seq;
+                next = match other.pointer(i, value) {
+                    0 => None,
+                    seq => Some(self.node(seq).await?),
+                };
+            }
+            if bucket.iter().any(|seq| *seq != 0) {
+                node.trie.insert(i, bucket);
+            }
+            i += 1;
+        }
+        self.metadata.append(&node.encode()).await?;
+        Ok(())
+    }
+
+    /// The `Stat` of every visible path, from the newest node of each.
+    async fn entries(&self) -> Result<BTreeMap<String, Stat>> {
+        let len = self.metadata.len().await;
+        if len > 1 {
+            let (_, done) = self.metadata.download(vec![1..len]).await;
+            done.await??;
+        }
+        let mut seen = HashSet::new();
+        let mut entries = BTreeMap::new();
+        for seq in (1..len).rev() {
+            let node = self.node(seq).await?;
+            if node.flags & HIDDEN_FLAG != 0 || !seen.insert(node.key.clone()) {
+                continue;
+            }
+            if let Some(value) = node.value {
+                entries.insert(node.key, Stat::decode(&value)?);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hyperdrive 10's encoding of the `Stat` of a 5 byte file, with the
+    /// `uid` and `gid` fields this module skips.
+    const FILE_STAT: &[u8] = &[
+        0x08, 0xa4, 0x83, 0x02, 0x10, 0x00, 0x18, 0x00, 0x20, 0x05, 0x28, 0x01, 0x30, 0x00, 0x38,
+        0x00, 0x40, 0xe8, 0x07, 0x48, 0xe8, 0x07,
+    ];
+
+    #[test]
+    fn decode_stat_fixture() {
+        let stat = Stat::decode(FILE_STAT).unwrap();
+        let expected = Stat {
+            mode: FILE_MODE,
+            size: 5,
+            blocks: 1,
+            offset: 0,
+            byte_offset: 0,
+            mtime: 1000,
+            ctime: 1000,
+        };
+        assert_eq!(stat, expected);
+        assert!(!stat.is_dir());
+        assert_eq!(Stat::decode(&stat.encode()).unwrap(), stat);
+    }
+
+    #[test]
+    fn trie_round_trip() {
+        // Element 3 points to block 1 for value 0 and block 2 for value 2,
+        // element 70 to block 300 for value 4.
+        let bytes = [0x03, 0x05, 0x01, 0x02, 0x46, 0x10, 0xac, 0x02];
+        let trie = decode_trie(&bytes).unwrap();
+        assert_eq!(trie[&3], [1, 0, 2, 0, 0]);
+        assert_eq!(trie[&70], [0, 0, 0, 0, 300]);
+        assert_eq!(encode_trie(&trie), bytes);
+    }
+
+    #[test]
+    fn reject_invalid_bucket() {
+        assert!(decode_trie(&[0x03, 0x20, 0x01]).is_err());
+    }
+
+    #[test]
+    fn node_round_trip() {
+        let mut node = TrieNode::new(2, "dir/a".to_string(), Some(FILE_STAT.to_vec()), 0);
+        node.trie.insert(1, [0, 1, 0, 0, 0]);
+        let decoded = TrieNode::decode(2, &node.encode()).unwrap();
+        assert_eq!(decoded.key, "dir/a");
+        assert_eq!(decoded.value.as_deref(), Some(FILE_STAT));
+        assert_eq!(decoded.trie, node.trie);
+        assert_eq!(decoded.hash, node.hash);
+    }
+
+    #[test]
+    fn path_elements() {
+        let node = TrieNode::new(1, "dir/a".to_string(), None, 0);
+        // One hash per component, the hidden flag and the terminator.
+        assert_eq!(node.hash.len(), 16);
+        assert_eq!(node.path_len(), 66);
+        assert_eq!(node.path(0), 0);
+        assert_eq!(node.path(65), TERMINATOR);
+        assert!((1..65).all(|i| node.path(i) < 4));
+        let hidden = TrieNode::new(1, "dir/a".to_string(), None, HIDDEN_FLAG);
+        assert_eq!(hidden.path(0), 1);
+        assert_eq!(
+            (1..66).map(|i| hidden.path(i)).collect::<Vec<_>>(),
+            (1..66).map(|i| node.path(i)).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod web_tests {
+    use super::*;
+    use crate::header::FeedHeader;
+    use crate::hypercore::open_writable_feed;
+    use wasm_bindgen_test::*;
+
+    /// A new drive on writable feeds. `Drive::open` would open the content
+    /// feed by its key, read-only.
+    async fn drive() -> Drive {
+        let name = format!("test-drive-{}", js_sys::Math::random());
+        let metadata = open_writable_feed(&format!("{}-metadata", name))
+            .await
+            .unwrap();
+        let content = open_writable_feed(&format!("{}-content", name))
+            .await
+            .unwrap();
+        let header = FeedHeader {
+            content_type: TRIE_TYPE.to_string(),
+            schema: Some(DRIVE_SUBTYPE.to_string()),
+            metadata: Some(content.key().to_vec()),
+        };
+        metadata.write_header(&header).await.unwrap();
+        Drive {
+            metadata,
+            content,
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    #[wasm_bindgen_test]
+    async fn lookup_follows_trie() {
+        let drive = drive().await;
+        let paths: Vec<String> = (0..20)
+            .map(|i| format!("dir/{}/file-{}", i % 3, i))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            drive
+                .write_file(path, path.as_bytes(), i as u64)
+                .await
+                .unwrap();
+        }
+        drive.write_file("dir/0/file-0", b"new", 100).await.unwrap();
+        assert_eq!(drive.read_file("dir/0/file-0").await.unwrap(), b"new");
+        for path in &paths[1..] {
+            assert_eq!(&drive.read_file(path).await.unwrap(), path.as_bytes());
+        }
+        assert_eq!(drive.stat("dir/0/file-0").await.unwrap().mtime, 100);
+        assert!(drive.stat("dir/1").await.unwrap().is_dir());
+        assert_eq!(drive.readdir("dir").await.unwrap(), vec!["0", "1", "2"]);
+        let err = drive.stat("dir/0/missing").await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DriveError>(),
+            Some(DriveError::NotFound { .. })
+        ));
+    }
+
+    #[wasm_bindgen_test]
+    async fn read_empty_file() {
+        let drive = drive().await;
+        drive.write_file("/empty", b"", 0).await.unwrap();
+        let stat = drive.stat("empty").await.unwrap();
+        assert_eq!((stat.size, stat.blocks), (0, 0));
+        assert!(drive.read_file("empty").await.unwrap().is_empty());
+    }
+}
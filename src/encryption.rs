@@ -0,0 +1,456 @@
+//! At-rest encryption for any `RandomAccess` store.
+//!
+//! Data is split into pages of `PAGE_SIZE` bytes. Each page is sealed with a
+//! fresh random nonce and stored as `[nonce][ciphertext + tag]`, with the
+//! page index as associated data so that pages can't be swapped around.
+//! The nonce field is sized for XChaCha20-Poly1305, AES-GCM pages only use
+//! its first 12 bytes and leave the rest zero.
+//!
+//! Every write re-seals each page it touches, so a 1 byte bitfield update
+//! costs a page decrypt and encrypt. The sealed pages go through the
+//! write-back cache below, which batches them into few IndexedDB
+//! transactions, but the crypto work is done per write.
+//!
+//! A stored page of only zero bytes is a hole and reads as zeros. Writes
+//! past the end leave the pages they skip as holes, and deleting whole
+//! pages leaves holes in the backend instead of sealed zeros, so the space
+//! is freed where the backend supports it. A sealed page is never all
+//! zeros, as its nonce is random.
+//!
+//! Whether the stores are encrypted is recorded by `schema`, together with
+//! a check value sealed with the key, so that opening with a missing or
+//! wrong key fails instead of mixing plaintext and ciphertext.
+
+use async_trait::async_trait;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use js_sys::Uint8Array;
+use random_access_storage::RandomAccess;
+use std::cell::RefCell;
+use std::fmt::{self, Debug};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+
+use crate::persistence::{count_failure, read_backend, storage_write, StorageError};
+
+/// Plaintext bytes per page.
+const PAGE_SIZE: u64 = 4096;
+/// Size of the nonce field of a stored page.
+const NONCE_SIZE: u64 = 24;
+/// Nonce size of AES-GCM.
+const WEB_CRYPTO_NONCE_SIZE: u64 = 12;
+const TAG_SIZE: u64 = 16;
+/// Bytes added to every stored page.
+const OVERHEAD: u64 = NONCE_SIZE + TAG_SIZE;
+const STORED_PAGE_SIZE: u64 = PAGE_SIZE + OVERHEAD;
+
+type Error = Box<dyn std::error::Error + Sync + Send>;
+
+/// Store holding the salt for passphrase derived keys. It is not encrypted.
+const SALT_ID: &str = "salt";
+const SALT_SIZE: u64 = 16;
+/// Name the WebCrypto storage key is registered under in JS.
+const CRYPTO_KEY_ID: &str = "storage";
+/// Plaintext of the key check value.
+const CHECK_PLAINTEXT: &[u8; 32] = b"hypercore-wasm storage key check";
+/// Associated data of the key check value, no page index is this long.
+const CHECK_AAD: &[u8] = b"check";
+/// Size of the sealed key check value.
+pub const CHECK_SIZE: u64 = NONCE_SIZE + 32 + TAG_SIZE;
+
+thread_local! {
+    static STORAGE_CIPHER: RefCell<Option<Cipher>> = RefCell::new(None);
+}
+
+/// The cipher for the feed stores, if encryption was enabled.
+pub fn storage_cipher() -> Option<Cipher> {
+    STORAGE_CIPHER.with(|cipher| cipher.borrow().clone())
+}
+
+/// Encrypt the feed stores with a key derived from `passphrase`.
+pub async fn use_passphrase(passphrase: &str) -> Result<(), StorageError> {
+    let salt = match read_backend(SALT_ID, 0, SALT_SIZE).await {
+        Ok(salt) if salt.len() as u64 == SALT_SIZE && salt.iter().any(|b| *b != 0) => salt,
+        _ => {
+            let salt = crypto_random_bytes(SALT_SIZE as u32).to_vec();
+            if let Err(err) = storage_write(SALT_ID, 0, &salt).await {
+                return Err(StorageError::from_js(SALT_ID, &err));
+            }
+            salt
+        }
+    };
+    let cipher = Cipher::from_passphrase(passphrase, &salt).await?;
+    STORAGE_CIPHER.with(|c| c.replace(Some(cipher)));
+    Ok(())
+}
+
+/// Encrypt the feed stores with a WebCrypto AES-GCM `CryptoKey`, which may
+/// be non-extractable.
+pub fn use_crypto_key(key: &JsValue) {
+    crypto_set_key(CRYPTO_KEY_ID, key);
+    let cipher = Cipher::WebCrypto {
+        key_id: CRYPTO_KEY_ID.to_string(),
+    };
+    STORAGE_CIPHER.with(|c| c.replace(Some(cipher)));
+}
+
+#[wasm_bindgen(module = "/callbacks.js")]
+extern "C" {
+    /// Register a WebCrypto key under `key_id`.
+    fn crypto_set_key(key_id: &str, key: &JsValue);
+
+    /// Fill a new array with cryptographically secure random bytes.
+    fn crypto_random_bytes(length: u32) -> Uint8Array;
+
+    /// Derive a 32 byte key from a passphrase with PBKDF2.
+    #[wasm_bindgen(catch)]
+    async fn crypto_derive_key(passphrase: &str, salt: &[u8]) -> Result<JsValue, JsValue>;
+
+    /// AES-GCM encrypt with the registered WebCrypto key `key_id`.
+    #[wasm_bindgen(catch)]
+    async fn crypto_seal(
+        key_id: &str,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Result<JsValue, JsValue>;
+
+    /// AES-GCM decrypt with the registered WebCrypto key `key_id`.
+    #[wasm_bindgen(catch)]
+    async fn crypto_open(
+        key_id: &str,
+        nonce: &[u8],
+        aad: &[u8],
+        data: &[u8],
+    ) -> Result<JsValue, JsValue>;
+}
+
+/// The cipher used to seal pages.
+#[derive(Clone)]
+pub enum Cipher {
+    /// XChaCha20-Poly1305 with a key held in WASM memory, e.g. one derived
+    /// from a passphrase.
+    XChaCha20Poly1305([u8; 32]),
+    /// AES-GCM with a non-extractable WebCrypto key registered in JS under
+    /// `key_id`. The key never enters WASM memory.
+    WebCrypto { key_id: String },
+}
+
+impl Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cipher::XChaCha20Poly1305(_) => write!(f, "Cipher::XChaCha20Poly1305"),
+            Cipher::WebCrypto { key_id } => write!(f, "Cipher::WebCrypto({})", key_id),
+        }
+    }
+}
+
+impl Cipher {
+    /// Derive an XChaCha20-Poly1305 key from a passphrase and salt.
+    pub async fn from_passphrase(passphrase: &str, salt: &[u8]) -> Result<Self, StorageError> {
+        let key = crypto_derive_key(passphrase, salt)
+            .await
+            .map_err(|err| StorageError::from_js("crypto", &err))?;
+        let key: Uint8Array = key.unchecked_into();
+        let mut bytes = [0u8; 32];
+        key.copy_to(&mut bytes);
+        Ok(Cipher::XChaCha20Poly1305(bytes))
+    }
+
+    /// Bytes of the nonce field the cipher uses.
+    fn nonce_size(&self) -> u64 {
+        match self {
+            Cipher::XChaCha20Poly1305(_) => NONCE_SIZE,
+            Cipher::WebCrypto { .. } => WEB_CRYPTO_NONCE_SIZE,
+        }
+    }
+
+    /// Seal `data` with a fresh nonce into `[nonce field][ciphertext + tag]`.
+    async fn seal_box(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let nonce_size = self.nonce_size() as usize;
+        let mut bytes = crypto_random_bytes(nonce_size as u32).to_vec();
+        let sealed = self.seal(&bytes, aad, data).await?;
+        bytes.resize(NONCE_SIZE as usize, 0);
+        bytes.extend_from_slice(&sealed);
+        Ok(bytes)
+    }
+
+    /// Open what `seal_box` sealed.
+    async fn open_box(&self, aad: &[u8], bytes: &[u8]) -> Result<Vec<u8>, StorageError> {
+        if (bytes.len() as u64) < NONCE_SIZE {
+            return Err(StorageError::Encryption {
+                message: "Sealed data is truncated".to_string(),
+            });
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_SIZE as usize);
+        self.open(&nonce[..self.nonce_size() as usize], aad, sealed)
+            .await
+    }
+
+    /// A value sealed with the key, to recognise the key when reopening.
+    pub async fn check_value(&self) -> Result<Vec<u8>, StorageError> {
+        self.seal_box(CHECK_AAD, CHECK_PLAINTEXT).await
+    }
+
+    /// Whether `check` was created by `check_value` with the same key.
+    pub async fn verify_check_value(&self, check: &[u8]) -> bool {
+        match self.open_box(CHECK_AAD, check).await {
+            Ok(plain) => plain == CHECK_PLAINTEXT,
+            Err(_) => false,
+        }
+    }
+
+    /// Whether `bytes` are the first stored page of a store encrypted with
+    /// this key.
+    pub async fn opens_first_page(&self, bytes: &[u8]) -> bool {
+        let length = bytes.len().min(STORED_PAGE_SIZE as usize);
+        self.open_box(&0u64.to_be_bytes(), &bytes[..length])
+            .await
+            .is_ok()
+    }
+
+    async fn seal(&self, nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        match self {
+            Cipher::XChaCha20Poly1305(key) => XChaCha20Poly1305::new(Key::from_slice(key))
+                .encrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+                .map_err(|_| StorageError::Encryption {
+                    message: "Sealing failed".to_string(),
+                }),
+            Cipher::WebCrypto { key_id } => crypto_seal(key_id, nonce, aad, data)
+                .await
+                .map(|value| value.unchecked_into::<Uint8Array>().to_vec())
+                .map_err(|err| StorageError::from_js("crypto", &err)),
+        }
+    }
+
+    async fn open(&self, nonce: &[u8], aad: &[u8], data: &[u8]) -> Result<Vec<u8>, StorageError> {
+        let failed = || StorageError::Encryption {
+            message: "Page could not be decrypted, wrong key or corrupt data".to_string(),
+        };
+        match self {
+            Cipher::XChaCha20Poly1305(key) => XChaCha20Poly1305::new(Key::from_slice(key))
+                .decrypt(XNonce::from_slice(nonce), Payload { msg: data, aad })
+                .map_err(|_| failed()),
+            Cipher::WebCrypto { key_id } => crypto_open(key_id, nonce, aad, data)
+                .await
+                .map(|value| value.unchecked_into::<Uint8Array>().to_vec())
+                .map_err(|_| failed()),
+        }
+    }
+}
+
+/// Wraps a `RandomAccess` store and encrypts everything written to it. With
+/// no cipher, calls go straight through to the inner store.
+#[derive(Debug)]
+pub struct Encrypted<T> {
+    inner: T,
+    cipher: Option<Cipher>,
+}
+
+impl<T> Encrypted<T>
+where
+    T: RandomAccess<Error = Error> + Debug + Send,
+{
+    pub fn new(inner: T, cipher: Option<Cipher>) -> Self {
+        Self { inner, cipher }
+    }
+
+    /// The plaintext length for a stored length.
+    fn plain_len(stored: u64) -> u64 {
+        let full = stored / STORED_PAGE_SIZE;
+        let rest = stored % STORED_PAGE_SIZE;
+        full * PAGE_SIZE + rest.saturating_sub(OVERHEAD)
+    }
+
+    async fn read_page(&mut self, cipher: &Cipher, page: u64) -> Result<Vec<u8>, Error> {
+        let offset = page * STORED_PAGE_SIZE;
+        let stored = self.inner.len().await?;
+        if offset >= stored {
+            return Ok(vec![]);
+        }
+        let length = STORED_PAGE_SIZE.min(stored - offset);
+        let bytes = self.inner.read(offset, length).await?;
+        if bytes.iter().all(|byte| *byte == 0) {
+            return Ok(vec![0; length.saturating_sub(OVERHEAD) as usize]);
+        }
+        match cipher.open_box(&page.to_be_bytes(), &bytes).await {
+            Ok(page) => Ok(page),
+            Err(err) => {
+                count_failure();
+                Err(err.into())
+            }
+        }
+    }
+
+    async fn write_page(&mut self, cipher: &Cipher, page: u64, data: &[u8]) -> Result<(), Error> {
+        let bytes = cipher.seal_box(&page.to_be_bytes(), data).await?;
+        self.inner.write(page * STORED_PAGE_SIZE, &bytes).await
+    }
+
+    /// Pad a short last page to a whole page, before something is stored
+    /// after it.
+    async fn fill_last_page(&mut self, cipher: &Cipher, size: u64) -> Result<(), Error> {
+        if size % PAGE_SIZE == 0 {
+            return Ok(());
+        }
+        let page = size / PAGE_SIZE;
+        let mut plain = self.read_page(cipher, page).await?;
+        plain.resize(PAGE_SIZE as usize, 0);
+        self.write_page(cipher, page, &plain).await
+    }
+}
+
+#[async_trait(?Send)]
+impl<T> RandomAccess for Encrypted<T>
+where
+    T: RandomAccess<Error = Error> + Debug + Send,
+{
+    type Error = Error;
+
+    async fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Self::Error> {
+        let cipher = match self.cipher.clone() {
+            Some(cipher) => cipher,
+            None => return self.inner.write(offset, data).await,
+        };
+        let size = self.len().await?;
+        if offset / PAGE_SIZE > size / PAGE_SIZE {
+            // The pages in between are left as holes. Only the last page
+            // has to be filled up, as a short page must be the last one.
+            self.fill_last_page(&cipher, size).await?;
+        }
+        let end = offset + data.len() as u64;
+        let mut page = offset / PAGE_SIZE;
+        while page * PAGE_SIZE < end {
+            let page_start = page * PAGE_SIZE;
+            let mut plain = self.read_page(&cipher, page).await?;
+            let from = offset.max(page_start);
+            let to = end.min(page_start + PAGE_SIZE);
+            let needed = (to - page_start) as usize;
+            if plain.len() < needed {
+                plain.resize(needed, 0);
+            }
+            plain[(from - page_start) as usize..needed]
+                .copy_from_slice(&data[(from - offset) as usize..(to - offset) as usize]);
+            self.write_page(&cipher, page, &plain).await?;
+            page += 1;
+        }
+        Ok(())
+    }
+
+    async fn read(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, Self::Error> {
+        let cipher = match self.cipher.clone() {
+            Some(cipher) => cipher,
+            None => return self.inner.read(offset, length).await,
+        };
+        let size = self.len().await?;
+        if offset + length > size {
+            return Err(StorageError::OutOfBounds {
+                id: "encrypted".to_string(),
+                offset,
+                length,
+                size,
+            }
+            .into());
+        }
+        let end = offset + length;
+        let mut data = Vec::with_capacity(length as usize);
+        let mut page = offset / PAGE_SIZE;
+        while page * PAGE_SIZE < end {
+            let page_start = page * PAGE_SIZE;
+            let plain = self.read_page(&cipher, page).await?;
+            let from = (offset.max(page_start) - page_start) as usize;
+            let to = (end.min(page_start + PAGE_SIZE) - page_start) as usize;
+            data.extend_from_slice(&plain[from..to]);
+            page += 1;
+        }
+        Ok(data)
+    }
+
+    async fn read_to_writer(
+        &mut self,
+        offset: u64,
+        length: u64,
+        buf: &mut (impl async_std::io::Write + Send),
+    ) -> Result<(), Self::Error> {
+        if self.cipher.is_none() {
+            return self.inner.read_to_writer(offset, length, buf).await;
+        }
+        Err(StorageError::Backend {
+            id: "encrypted".to_string(),
+            name: "NotSupportedError".to_string(),
+            message: "Encrypted stores can't be read to a writer".to_string(),
+        }
+        .into())
+    }
+
+    async fn del(&mut self, offset: u64, length: u64) -> Result<(), Self::Error> {
+        if self.cipher.is_none() {
+            return self.inner.del(offset, length).await;
+        }
+        let size = self.len().await?;
+        let end = (offset + length).min(size);
+        if offset >= end {
+            return Ok(());
+        }
+        if end == size {
+            return self.truncate(offset).await;
+        }
+        // Whole pages are deleted from the backend and become holes, the
+        // pages the range only partly covers are re-sealed with zeros.
+        let first = (offset + PAGE_SIZE - 1) / PAGE_SIZE;
+        let last = end / PAGE_SIZE;
+        if first >= last {
+            return self.write(offset, &vec![0; (end - offset) as usize]).await;
+        }
+        if offset < first * PAGE_SIZE {
+            self.write(offset, &vec![0; (first * PAGE_SIZE - offset) as usize])
+                .await?;
+        }
+        if last * PAGE_SIZE < end {
+            self.write(
+                last * PAGE_SIZE,
+                &vec![0; (end - last * PAGE_SIZE) as usize],
+            )
+            .await?;
+        }
+        self.inner
+            .del(first * STORED_PAGE_SIZE, (last - first) * STORED_PAGE_SIZE)
+            .await
+    }
+
+    async fn truncate(&mut self, length: u64) -> Result<(), Self::Error> {
+        let cipher = match self.cipher.clone() {
+            Some(cipher) => cipher,
+            None => return self.inner.truncate(length).await,
+        };
+        let page = length / PAGE_SIZE;
+        let rest = length % PAGE_SIZE;
+        let size = self.len().await?;
+        if page > size / PAGE_SIZE {
+            self.fill_last_page(&cipher, size).await?;
+        }
+        if rest == 0 {
+            return self.inner.truncate(page * STORED_PAGE_SIZE).await;
+        }
+        let mut plain = self.read_page(&cipher, page).await?;
+        plain.resize(rest as usize, 0);
+        self.inner.truncate(page * STORED_PAGE_SIZE).await?;
+        self.write_page(&cipher, page, &plain).await
+    }
+
+    async fn len(&self) -> Result<u64, Self::Error> {
+        let stored = self.inner.len().await?;
+        match self.cipher {
+            Some(_) => Ok(Self::plain_len(stored)),
+            None => Ok(stored),
+        }
+    }
+
+    async fn is_empty(&mut self) -> Result<bool, Self::Error> {
+        self.inner.is_empty().await
+    }
+
+    async fn sync_all(&mut self) -> Result<(), Self::Error> {
+        self.inner.sync_all().await
+    }
+}
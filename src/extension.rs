@@ -0,0 +1,82 @@
+//! Named extension messages on the channels of a feed.
+//!
+//! Both sides announce the names of their extensions with an `Options`
+//! message when the channel opens. An `Extension` message carries the index
+//! of its name in the sender's sorted list of names. Extensions registered
+//! after a channel opened aren't announced on it, so outgoing ids use the
+//! names announced on the channel and incoming ids the names the remote
+//! announced.
+
+use futures::channel::mpsc;
+use js_sys::{Function, Uint8Array};
+use log::*;
+use std::collections::{BTreeMap, HashMap};
+use wasm_bindgen::JsValue;
+
+use crate::download::PeerId;
+
+/// The extensions of one feed and the channels to send them on.
+#[derive(Debug, Default)]
+pub struct Extensions {
+    /// Handlers by name.
+    handlers: BTreeMap<String, Function>,
+    /// Outgoing messages for the replication task of every peer.
+    peers: HashMap<PeerId, mpsc::UnboundedSender<(String, Vec<u8>)>>,
+}
+
+impl Extensions {
+    /// Register an extension. `handler` is called with the payload as a
+    /// `Uint8Array` and the id of the sending peer. Only channels opened
+    /// afterwards announce the extension.
+    pub fn register(&mut self, name: &str, handler: Function) {
+        self.handlers.insert(name.to_string(), handler);
+    }
+
+    /// Register a replication task. Returns the sorted names to announce to
+    /// its peer, which give the ids of the messages sent on the channel, and
+    /// the receiver of the messages to send.
+    pub fn add_peer(
+        &mut self,
+        id: PeerId,
+    ) -> (Vec<String>, mpsc::UnboundedReceiver<(String, Vec<u8>)>) {
+        let (tx, rx) = mpsc::unbounded();
+        self.peers.insert(id, tx);
+        (self.handlers.keys().cloned().collect(), rx)
+    }
+
+    pub fn remove_peer(&mut self, id: PeerId) {
+        self.peers.remove(&id);
+    }
+
+    /// Queue a message to one peer. Returns whether the peer is connected.
+    pub fn send(&self, peer: PeerId, name: &str, payload: Vec<u8>) -> bool {
+        match self.peers.get(&peer) {
+            Some(tx) => tx.unbounded_send((name.to_string(), payload)).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Queue a message to all peers. Returns the number of peers.
+    pub fn broadcast(&self, name: &str, payload: &[u8]) -> usize {
+        self.peers
+            .values()
+            .filter(|tx| {
+                tx.unbounded_send((name.to_string(), payload.to_vec()))
+                    .is_ok()
+            })
+            .count()
+    }
+
+    /// Pass a received message to the handler of its extension.
+    pub fn on_message(&self, name: &str, peer: PeerId, payload: &[u8]) {
+        let handler = match self.handlers.get(name) {
+            Some(handler) => handler,
+            None => return,
+        };
+        let payload = Uint8Array::from(payload);
+        let peer = JsValue::from_f64(peer as f64);
+        if let Err(err) = handler.call2(&JsValue::NULL, &payload, &peer) {
+            error!("extension {} handler failed: {:?}", name, err);
+        }
+    }
+}
@@ -0,0 +1,1009 @@
+use futures::channel::mpsc;
+use futures::channel::oneshot::Canceled;
+use futures::stream::StreamExt;
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+use log::*;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{future_to_promise, spawn_local};
+
+use crate::auth::PeerAuth;
+use crate::autobase::{Autobase, ViewEntry};
+use crate::bee::{Bee, Entry, RangeOptions};
+use crate::download::{ranges_from_indices, DownloadId};
+use crate::drive::{Drive, Stat};
+use crate::header::FeedHeader;
+use crate::hypercore::{self, FeedStore, FeedWrapper, ReplicateHooks, ReplicateOptions};
+use crate::persistence::FeedStorage;
+use crate::resolver::KeyResolver;
+use crate::utils::{js_index, to_js_error};
+use crate::AppEvent;
+
+/// A browser-side feed, as handed out to JavaScript.
+///
+/// Methods that touch the feed return a `Promise`, as the feed is shared
+/// with the replication tasks and has to be locked first.
+#[wasm_bindgen]
+pub struct FeedHandle {
+    feed: FeedWrapper<FeedStorage>,
+    on_append: Rc<RefCell<Option<Function>>>,
+    on_feed: Rc<RefCell<Option<Function>>>,
+    hooks: RefCell<ReplicateHooks>,
+}
+
+impl FeedHandle {
+    pub fn new(feed: FeedWrapper<FeedStorage>) -> Self {
+        Self {
+            feed,
+            on_append: Rc::new(RefCell::new(None)),
+            on_feed: Rc::new(RefCell::new(None)),
+            hooks: RefCell::new(ReplicateHooks::default()),
+        }
+    }
+}
+
+impl FeedHandle {
+    fn select(&self, ranges: Vec<Range<u64>>) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let (id, done) = feed.download(ranges).await;
+            let done = future_to_promise(async move {
+                match done.await {
+                    Ok(result) => result.map_err(|err| to_js_error(&err))?,
+                    Err(Canceled) => {
+                        let err = js_sys::Error::new("Download was cancelled");
+                        err.set_name("AbortError");
+                        return Err(err.into());
+                    }
+                }
+                Ok(JsValue::UNDEFINED)
+            });
+            Ok(Download { id, feed, done }.into())
+        })
+    }
+}
+
+/// A pending download of selected blocks.
+#[wasm_bindgen]
+pub struct Download {
+    id: DownloadId,
+    feed: FeedWrapper<FeedStorage>,
+    done: Promise,
+}
+
+#[wasm_bindgen]
+impl Download {
+    /// Resolves once all selected blocks are stored locally. Rejects with an
+    /// `AbortError` if the download is cancelled.
+    pub fn done(&self) -> Promise {
+        self.done.clone()
+    }
+
+    /// Stop downloading the selected blocks.
+    pub fn cancel(&self) {
+        let feed = self.feed.clone();
+        let id = self.id;
+        spawn_local(async move {
+            feed.cancel_download(id).await;
+        });
+    }
+}
+
+/// Replicate the feeds of `feedstore` with the peer at the websocket
+/// address `addr`. Returns once the initial sync is done, and keeps
+/// dispatching events to the callbacks in the background afterwards.
+async fn replicate_feeds(
+    addr: &str,
+    feedstore: FeedStore,
+    options: ReplicateOptions,
+    hooks: ReplicateHooks,
+    on_append: Rc<RefCell<Option<Function>>>,
+    on_feed: Rc<RefCell<Option<Function>>>,
+) -> Result<(), JsValue> {
+    let mut replication = Replication::start(addr, feedstore, options, hooks).await?;
+    replication.synced(&on_append, &on_feed).await?;
+    replication.dispatch(on_append, on_feed);
+    Ok(())
+}
+
+/// A running replication whose events are not dispatched yet.
+struct Replication {
+    app_rx: mpsc::UnboundedReceiver<AppEvent>,
+    /// Opens further feeds on the connection.
+    added: mpsc::UnboundedSender<FeedWrapper<FeedStorage>>,
+    /// Discovery keys of the started and added feeds that didn't sync yet.
+    unsynced: HashSet<[u8; 32]>,
+}
+
+impl Replication {
+    async fn start(
+        addr: &str,
+        feedstore: FeedStore,
+        options: ReplicateOptions,
+        hooks: ReplicateHooks,
+    ) -> Result<Self, JsValue> {
+        let protocol = hypercore::connect(addr)
+            .await
+            .map_err(|err| to_js_error(&err))?;
+        let unsynced = feedstore
+            .feeds()
+            .iter()
+            .map(|feed| feed.discovery_key())
+            .collect();
+        let (app_tx, app_rx) = mpsc::unbounded();
+        let added = hypercore::spawn_replicate(protocol, feedstore, options, hooks, app_tx);
+        Ok(Self {
+            app_rx,
+            added,
+            unsynced,
+        })
+    }
+
+    /// Replicate `feed` too.
+    fn add(&mut self, feed: FeedWrapper<FeedStorage>) -> Result<(), JsValue> {
+        self.unsynced.insert(feed.discovery_key());
+        self.added
+            .unbounded_send(feed)
+            .map_err(|_| js_sys::Error::new("Replication ended").into())
+    }
+
+    /// Wait for the initial sync of all started and added feeds. Feeds the
+    /// remote opened are not waited for.
+    async fn synced(
+        &mut self,
+        on_append: &RefCell<Option<Function>>,
+        on_feed: &RefCell<Option<Function>>,
+    ) -> Result<(), JsValue> {
+        while !self.unsynced.is_empty() {
+            match self.app_rx.next().await {
+                Some(AppEvent::Synced { discovery_key, .. }) => {
+                    self.unsynced.remove(&discovery_key);
+                }
+                Some(AppEvent::Appended { index, data }) => {
+                    dispatch_append(on_append, index, &data)
+                }
+                Some(AppEvent::InvalidData { index, reason }) => {
+                    let message = format!("Invalid data for block {}: {}", index, reason);
+                    return Err(js_sys::Error::new(&message).into());
+                }
+                Some(AppEvent::Opened(feed)) => dispatch_feed(on_feed, feed),
+                Some(AppEvent::Error(err)) => return Err(to_js_error(&err)),
+                None => return Err(js_sys::Error::new("Replication ended").into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep dispatching events to the callbacks in the background.
+    fn dispatch(
+        self,
+        on_append: Rc<RefCell<Option<Function>>>,
+        on_feed: Rc<RefCell<Option<Function>>>,
+    ) {
+        let mut app_rx = self.app_rx;
+        spawn_local(async move {
+            while let Some(event) = app_rx.next().await {
+                match event {
+                    AppEvent::Appended { index, data } => dispatch_append(&on_append, index, &data),
+                    AppEvent::InvalidData { index, reason } => {
+                        warn!("dropped invalid block {}: {}", index, reason)
+                    }
+                    AppEvent::Opened(feed) => dispatch_feed(&on_feed, feed),
+                    AppEvent::Error(err) => error!("replication error: {:#}", err),
+                    AppEvent::Synced { .. } => {}
+                }
+            }
+        });
+    }
+}
+
+/// Parse hex encoded feed public keys.
+pub(crate) fn parse_keys(keys: &[JsValue]) -> Result<Vec<[u8; 32]>, JsValue> {
+    keys.iter()
+        .map(|key| {
+            let key = key
+                .as_string()
+                .ok_or_else(|| js_sys::Error::new("Feed keys must be hex strings"))?;
+            let key = hex::decode(&key).map_err(|err| js_sys::Error::new(&err.to_string()))?;
+            key.try_into()
+                .map_err(|_| js_sys::Error::new("Invalid key length").into())
+        })
+        .collect()
+}
+
+/// Read `{ wait, timeout }` of the options of `get`.
+fn get_options(options: &JsValue) -> Result<(bool, Option<u32>), JsValue> {
+    if !options.is_object() {
+        return Ok((true, None));
+    }
+    let wait = Reflect::get(options, &"wait".into())?;
+    let timeout = Reflect::get(options, &"timeout".into())?;
+    Ok((
+        wait.as_bool().unwrap_or(true),
+        timeout.as_f64().map(|ms| ms as u32),
+    ))
+}
+
+/// Call the feed callback, if one is set, with a handle of the opened feed.
+fn dispatch_feed(on_feed: &RefCell<Option<Function>>, feed: FeedWrapper<FeedStorage>) {
+    if let Some(callback) = on_feed.borrow().as_ref() {
+        let handle = JsValue::from(FeedHandle::new(feed));
+        if let Err(err) = callback.call1(&JsValue::NULL, &handle) {
+            error!("feed callback failed: {:?}", err);
+        }
+    }
+}
+
+/// Call the append callback, if one is set, with `(index, data)`.
+fn dispatch_append(on_append: &RefCell<Option<Function>>, index: u64, data: &[u8]) {
+    if let Some(callback) = on_append.borrow().as_ref() {
+        let index = JsValue::from_f64(index as f64);
+        let data = Uint8Array::from(data);
+        if let Err(err) = callback.call2(&JsValue::NULL, &index, &data) {
+            error!("append callback failed: {:?}", err);
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl FeedHandle {
+    /// Resolves to the number of blocks in the feed.
+    pub fn len(&self) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move { Ok(JsValue::from_f64(feed.len().await as f64)) })
+    }
+
+    /// Replicate the feed with the peer at the websocket address `addr`.
+    ///
+    /// Resolves once everything the peer had is downloaded. With `live` set,
+    /// the feed keeps following the peer afterwards and calls the callback
+    /// set with `set_on_append` for every new block. With `sparse` set, only
+    /// blocks selected with `download` are fetched.
+    pub fn replicate(&self, addr: String, live: bool, sparse: bool) -> Promise {
+        let feed = self.feed.clone();
+        let on_append = self.on_append.clone();
+        let on_feed = self.on_feed.clone();
+        let hooks = self.hooks.borrow().clone();
+        future_to_promise(async move {
+            let mut feedstore = FeedStore::new();
+            feedstore.add(feed);
+            let options = ReplicateOptions { live, sparse };
+            replicate_feeds(&addr, feedstore, options, hooks, on_append, on_feed).await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Resolves to the block at `index` as a `Uint8Array`.
+    ///
+    /// `options` may set `wait` (default `true`) to fetch a missing block
+    /// from the connected peers, and `timeout` in milliseconds to give up
+    /// waiting. Without `wait`, a missing block resolves to `undefined`.
+    pub fn get(&self, index: f64, options: JsValue) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let index = js_index(index, "index")?;
+            let (wait, timeout) = get_options(&options)?;
+            let block = feed
+                .get(index, wait, timeout)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(match block {
+                Some(data) => Uint8Array::from(&data[..]).into(),
+                None => JsValue::UNDEFINED,
+            })
+        })
+    }
+
+    /// Resolves to `[index, offset]`: the block holding byte `byte_offset`
+    /// of the feed and the offset of the byte within it. `options` are the
+    /// same as for `get`, missing tree nodes are fetched by downloading a
+    /// block.
+    pub fn seek(&self, byte_offset: f64, options: JsValue) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let byte_offset = js_index(byte_offset, "byteOffset")?;
+            let (wait, timeout) = get_options(&options)?;
+            let (index, offset) = feed
+                .seek(byte_offset, wait, timeout)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            let result = Array::new();
+            result.push(&(index as f64).into());
+            result.push(&(offset as f64).into());
+            Ok(result.into())
+        })
+    }
+
+    /// Resolves to `length` bytes of the feed from byte `byte_offset` on as
+    /// a `Uint8Array`, read across block boundaries. Like an HTTP range, the
+    /// result is cut off at the end of the feed. `options` are the same as
+    /// for `get`.
+    pub fn read_bytes(&self, byte_offset: f64, length: f64, options: JsValue) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let byte_offset = js_index(byte_offset, "byteOffset")?;
+            let length = js_index(length, "length")?;
+            let (wait, timeout) = get_options(&options)?;
+            let data = feed
+                .read_bytes(byte_offset, length, wait, timeout)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(Uint8Array::from(&data[..]).into())
+        })
+    }
+
+    /// Resolves to the header of the feed, `{ contentType, schema, metadata }`,
+    /// read from block 0, or `undefined` if the feed has none yet. `options`
+    /// are the same as for `get`.
+    pub fn header(&self, options: JsValue) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let (wait, timeout) = get_options(&options)?;
+            let header = feed
+                .header(wait, timeout)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            let header = match header {
+                Some(header) => header,
+                None => return Ok(JsValue::UNDEFINED),
+            };
+            let result = Object::new();
+            Reflect::set(&result, &"contentType".into(), &header.content_type.into())?;
+            let schema = header.schema.map_or(JsValue::NULL, JsValue::from);
+            Reflect::set(&result, &"schema".into(), &schema)?;
+            let metadata = header.metadata.map_or(JsValue::NULL, |metadata| {
+                Uint8Array::from(&metadata[..]).into()
+            });
+            Reflect::set(&result, &"metadata".into(), &metadata)?;
+            Ok(result.into())
+        })
+    }
+
+    /// Write the header as the first block of a writable, empty feed.
+    pub fn write_header(
+        &self,
+        content_type: String,
+        schema: Option<String>,
+        metadata: Option<Vec<u8>>,
+    ) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let header = FeedHeader {
+                content_type,
+                schema,
+                metadata,
+            };
+            feed.write_header(&header)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Download the blocks `start..end`. Resolves to a `Download`.
+    pub fn download(&self, start: f64, end: f64) -> Promise {
+        match (js_index(start, "start"), js_index(end, "end")) {
+            (Ok(start), Ok(end)) => self.select(vec![start..end]),
+            (Err(err), _) | (_, Err(err)) => Promise::reject(&err),
+        }
+    }
+
+    /// Download the blocks at `indices`. Resolves to a `Download`.
+    pub fn download_blocks(&self, indices: Vec<f64>) -> Promise {
+        let indices: Result<Vec<u64>, JsValue> = indices
+            .into_iter()
+            .map(|index| js_index(index, "index"))
+            .collect();
+        match indices {
+            Ok(indices) => self.select(ranges_from_indices(indices)),
+            Err(err) => Promise::reject(&err),
+        }
+    }
+
+    /// Only replicate with peers whose handshake public key is one of the
+    /// hex encoded `keys`. Applies to connections opened afterwards.
+    pub fn set_peer_allowlist(&self, keys: Box<[JsValue]>) -> Result<(), JsValue> {
+        self.hooks.borrow_mut().auth = PeerAuth::allowlist(&keys)?;
+        Ok(())
+    }
+
+    /// Decide on every handshake whether to replicate with a peer. The
+    /// callback gets the hex encoded public key and returns a boolean or a
+    /// `Promise` of one. Applies to connections opened afterwards.
+    pub fn set_peer_authorizer(&self, callback: Function) {
+        self.hooks.borrow_mut().auth = PeerAuth::Callback(callback);
+    }
+
+    /// Open feeds the remote announces if their public key is one of the
+    /// hex encoded `keys`. Applies to connections opened afterwards.
+    pub fn set_related_keys(&self, keys: Box<[JsValue]>) -> Result<(), JsValue> {
+        let keys = parse_keys(&keys)?;
+        self.hooks.borrow_mut().resolver = KeyResolver::Keys(keys);
+        Ok(())
+    }
+
+    /// Resolve feeds the remote announces that are not open yet. The
+    /// callback gets the hex encoded discovery key and returns the hex
+    /// encoded public key, a `Promise` of it, or `null` to ignore the feed.
+    /// Applies to connections opened afterwards.
+    pub fn set_key_resolver(&self, callback: Function) {
+        self.hooks.borrow_mut().resolver = KeyResolver::Callback(callback);
+    }
+
+    /// Set the callback called with a `FeedHandle` for every feed opened
+    /// through the key resolver.
+    pub fn set_on_feed(&self, callback: Function) {
+        self.on_feed.replace(Some(callback));
+    }
+
+    /// Register the extension `name`. `callback` is called with the payload
+    /// as a `Uint8Array` and the id of the sending peer, as listed by
+    /// `peers`. Extensions must be registered before replicating.
+    pub fn register_extension(&self, name: String, callback: Function) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            feed.register_extension(&name, callback).await;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Send an extension message to one peer. Resolves to whether the peer
+    /// is connected. Peers that don't support the extension ignore it.
+    pub fn send_extension(&self, name: String, peer: f64, payload: Vec<u8>) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let peer = js_index(peer, "peer")?;
+            let sent = feed.send_extension(peer, &name, payload).await;
+            Ok(JsValue::from_bool(sent))
+        })
+    }
+
+    /// Send an extension message to all connected peers. Resolves to the
+    /// number of peers.
+    pub fn broadcast_extension(&self, name: String, payload: Vec<u8>) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let count = feed.broadcast_extension(&name, &payload).await;
+            Ok(JsValue::from_f64(count as f64))
+        })
+    }
+
+    /// Set the callback called with `(index, data)` for blocks appended by
+    /// the remote during live replication.
+    pub fn set_on_append(&self, callback: Function) {
+        self.on_append.replace(Some(callback));
+    }
+
+    /// Resolves to an array with the replication statistics of every
+    /// connected peer: `{ id, remotePublicKey, remoteLength, uploadedBlocks,
+    /// uploadedBytes, downloadedBlocks, downloadedBytes, rtt, connectedAt }`.
+    /// The key is hex encoded, `rtt` is in milliseconds or `null` before the
+    /// first response, `connectedAt` in milliseconds since the epoch.
+    pub fn peers(&self) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let peers = Array::new();
+            for stats in feed.peers().await {
+                let peer = Object::new();
+                let key = hex::encode(&stats.remote_public_key);
+                Reflect::set(&peer, &"remotePublicKey".into(), &key.into())?;
+                let numbers = [
+                    ("id", stats.id),
+                    ("remoteLength", stats.remote_length),
+                    ("uploadedBlocks", stats.uploaded_blocks),
+                    ("uploadedBytes", stats.uploaded_bytes),
+                    ("downloadedBlocks", stats.downloaded_blocks),
+                    ("downloadedBytes", stats.downloaded_bytes),
+                ];
+                for (name, value) in numbers.iter() {
+                    Reflect::set(&peer, &(*name).into(), &(*value as f64).into())?;
+                }
+                let rtt = stats.rtt.map_or(JsValue::NULL, JsValue::from_f64);
+                Reflect::set(&peer, &"rtt".into(), &rtt)?;
+                Reflect::set(&peer, &"connectedAt".into(), &stats.connected_at.into())?;
+                peers.push(&peer);
+            }
+            Ok(peers.into())
+        })
+    }
+
+    /// Bytes the feed currently takes up in IndexedDB.
+    pub fn usage(&self) -> f64 {
+        self.feed.usage() as f64
+    }
+
+    /// Remove the downloaded data of blocks `start..end` to free up quota.
+    pub fn clear(&self, start: f64, end: f64) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let (start, end) = (js_index(start, "start")?, js_index(end, "end")?);
+            feed.clear(start, end)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Resolves to a `Uint8Array` archive of the feed's stores. The secret
+    /// key is only included if `include_secret_key` is set.
+    pub fn export(&self, include_secret_key: bool) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let bytes = feed
+                .export(include_secret_key)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(Uint8Array::from(&bytes[..]).into())
+        })
+    }
+
+    /// Check the stored blocks for corruption, e.g. after a crash in the
+    /// middle of a write. Resolves to `{ checked, corrupt, signatureValid }`
+    /// where `corrupt` lists the indices of bad blocks. With `repair` set,
+    /// these are cleared so that they get downloaded again.
+    pub fn verify(&self, repair: bool) -> Promise {
+        let feed = self.feed.clone();
+        future_to_promise(async move {
+            let report = feed.verify(repair).await.map_err(|err| to_js_error(&err))?;
+            let corrupt: Array = report
+                .corrupt
+                .iter()
+                .map(|index| JsValue::from_f64(*index as f64))
+                .collect();
+            let result = Object::new();
+            Reflect::set(&result, &"checked".into(), &(report.checked as f64).into())?;
+            Reflect::set(&result, &"corrupt".into(), &corrupt)?;
+            Reflect::set(
+                &result,
+                &"signatureValid".into(),
+                &report.signature_valid.into(),
+            )?;
+            Ok(result.into())
+        })
+    }
+}
+
+/// A hyperdrive-style filesystem, as handed out to JavaScript.
+#[wasm_bindgen]
+pub struct DriveHandle {
+    metadata: FeedWrapper<FeedStorage>,
+    /// Opened once the header of the metadata feed is available.
+    drive: Rc<RefCell<Option<Drive>>>,
+    auth: RefCell<PeerAuth>,
+}
+
+impl DriveHandle {
+    pub fn new(metadata: FeedWrapper<FeedStorage>, drive: Option<Drive>) -> Self {
+        Self {
+            metadata,
+            drive: Rc::new(RefCell::new(drive)),
+            auth: RefCell::new(PeerAuth::Any),
+        }
+    }
+
+    fn drive(&self) -> Result<Drive, JsValue> {
+        self.drive
+            .borrow()
+            .clone()
+            .ok_or_else(|| js_sys::Error::new("The drive was not replicated yet").into())
+    }
+}
+
+/// Convert a `Stat` to `{ mode, size, blocks, offset, byteOffset, mtime,
+/// ctime, isDirectory }`.
+fn stat_to_js(stat: &Stat) -> Result<JsValue, JsValue> {
+    let result = Object::new();
+    let numbers = [
+        ("mode", u64::from(stat.mode)),
+        ("size", stat.size),
+        ("blocks", stat.blocks),
+        ("offset", stat.offset),
+        ("byteOffset", stat.byte_offset),
+        ("mtime", stat.mtime),
+        ("ctime", stat.ctime),
+    ];
+    for (name, value) in numbers.iter() {
+        Reflect::set(&result, &(*name).into(), &(*value as f64).into())?;
+    }
+    Reflect::set(&result, &"isDirectory".into(), &stat.is_dir().into())?;
+    Ok(result.into())
+}
+
+#[wasm_bindgen]
+impl DriveHandle {
+    /// Only replicate with peers whose handshake public key is one of the
+    /// hex encoded `keys`, see `FeedHandle.set_peer_allowlist`.
+    pub fn set_peer_allowlist(&self, keys: Box<[JsValue]>) -> Result<(), JsValue> {
+        self.auth.replace(PeerAuth::allowlist(&keys)?);
+        Ok(())
+    }
+
+    /// Decide on every handshake whether to replicate with a peer, see
+    /// `FeedHandle.set_peer_authorizer`.
+    pub fn set_peer_authorizer(&self, callback: Function) {
+        self.auth.replace(PeerAuth::Callback(callback));
+    }
+
+    /// Replicate the drive with the peer at the websocket address `addr`.
+    ///
+    /// Replication is sparse: trie nodes and file contents are downloaded
+    /// as they are read.
+    /// If the header of the metadata feed is not stored yet, it is fetched
+    /// first, as it holds the key of the content feed. The content feed is
+    /// then added to the same connection.
+    pub fn replicate(&self, addr: String, live: bool) -> Promise {
+        let metadata = self.metadata.clone();
+        let cell = self.drive.clone();
+        let hooks = ReplicateHooks {
+            auth: self.auth.borrow().clone(),
+            ..ReplicateHooks::default()
+        };
+        future_to_promise(async move {
+            let existing = cell.borrow().clone();
+            let mut feedstore = FeedStore::new();
+            feedstore.add(metadata.clone());
+            if let Some(drive) = &existing {
+                feedstore.add(drive.content().clone());
+            }
+            let options = ReplicateOptions { live, sparse: true };
+            let mut replication = Replication::start(&addr, feedstore, options, hooks).await?;
+            let on_append: Rc<RefCell<Option<Function>>> = Rc::default();
+            let on_feed: Rc<RefCell<Option<Function>>> = Rc::default();
+            replication.synced(&on_append, &on_feed).await?;
+            if existing.is_none() {
+                let drive = Drive::open(metadata, true)
+                    .await
+                    .map_err(|err| to_js_error(&err))?
+                    .ok_or_else(|| js_sys::Error::new("The drive has no header"))?;
+                replication.add(drive.content().clone())?;
+                cell.replace(Some(drive));
+                replication.synced(&on_append, &on_feed).await?;
+            }
+            replication.dispatch(on_append, on_feed);
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Resolves to the metadata of the file or directory at `path`:
+    /// `{ mode, size, blocks, offset, byteOffset, mtime, ctime,
+    /// isDirectory }`. Rejects with a `NotFoundError` if there is none.
+    pub fn stat(&self, path: String) -> Promise {
+        let drive = self.drive();
+        future_to_promise(async move {
+            let stat = drive?.stat(&path).await.map_err(|err| to_js_error(&err))?;
+            stat_to_js(&stat)
+        })
+    }
+
+    /// Resolves to the sorted names of the entries of the directory `path`.
+    pub fn readdir(&self, path: String) -> Promise {
+        let drive = self.drive();
+        future_to_promise(async move {
+            let names = drive?
+                .readdir(&path)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(names
+                .into_iter()
+                .map(JsValue::from)
+                .collect::<Array>()
+                .into())
+        })
+    }
+
+    /// Resolves to the contents of the file at `path` as a `Uint8Array`.
+    pub fn read_file(&self, path: String) -> Promise {
+        let drive = self.drive();
+        future_to_promise(async move {
+            let data = drive?
+                .read_file(&path)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(Uint8Array::from(&data[..]).into())
+        })
+    }
+
+    /// Write `data` to the file at `path`. Both feeds of the drive must be
+    /// writable. Resolves to the `stat` of the new file.
+    ///
+    /// Only existing drives can be written to: a new drive can't be created
+    /// here, as its metadata header isn't written.
+    pub fn write_file(&self, path: String, data: Vec<u8>) -> Promise {
+        let drive = self.drive();
+        future_to_promise(async move {
+            let now = js_sys::Date::now() as u64;
+            let stat = drive?
+                .write_file(&path, &data, now)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            stat_to_js(&stat)
+        })
+    }
+}
+
+/// A key-value index stored in a feed, as handed out to JavaScript.
+///
+/// Keys and values are `Uint8Array`s and keys are ordered bytewise.
+#[wasm_bindgen]
+pub struct BeeHandle {
+    bee: Bee,
+    auth: RefCell<PeerAuth>,
+}
+
+impl BeeHandle {
+    pub fn new(bee: Bee) -> Self {
+        Self {
+            bee,
+            auth: RefCell::new(PeerAuth::Any),
+        }
+    }
+}
+
+/// Convert an `Entry` to `{ seq, key, value }`.
+fn entry_to_js(entry: &Entry) -> Result<JsValue, JsValue> {
+    let result = Object::new();
+    Reflect::set(&result, &"seq".into(), &(entry.seq as f64).into())?;
+    let key = Uint8Array::from(&entry.key[..]);
+    Reflect::set(&result, &"key".into(), &key)?;
+    let value = Uint8Array::from(&entry.value[..]);
+    Reflect::set(&result, &"value".into(), &value)?;
+    Ok(result.into())
+}
+
+/// Read a bound of the options of `range`, a `Uint8Array` or a string.
+fn range_bound(options: &JsValue, name: &str) -> Result<Option<Vec<u8>>, JsValue> {
+    let bound = Reflect::get(options, &name.into())?;
+    if bound.is_undefined() || bound.is_null() {
+        return Ok(None);
+    }
+    if let Some(bound) = bound.as_string() {
+        return Ok(Some(bound.into_bytes()));
+    }
+    match bound.dyn_into::<Uint8Array>() {
+        Ok(bound) => Ok(Some(bound.to_vec())),
+        Err(_) => Err(js_sys::Error::new(&format!("{} must be a Uint8Array", name)).into()),
+    }
+}
+
+#[wasm_bindgen]
+impl BeeHandle {
+    /// Only replicate with peers whose handshake public key is one of the
+    /// hex encoded `keys`, see `FeedHandle.set_peer_allowlist`.
+    pub fn set_peer_allowlist(&self, keys: Box<[JsValue]>) -> Result<(), JsValue> {
+        self.auth.replace(PeerAuth::allowlist(&keys)?);
+        Ok(())
+    }
+
+    /// Decide on every handshake whether to replicate with a peer, see
+    /// `FeedHandle.set_peer_authorizer`.
+    pub fn set_peer_authorizer(&self, callback: Function) {
+        self.auth.replace(PeerAuth::Callback(callback));
+    }
+
+    /// Replicate the index with the peer at the websocket address `addr`.
+    /// Replication is sparse: blocks are downloaded as they are read.
+    pub fn replicate(&self, addr: String, live: bool) -> Promise {
+        let feed = self.bee.feed().clone();
+        let hooks = ReplicateHooks {
+            auth: self.auth.borrow().clone(),
+            ..ReplicateHooks::default()
+        };
+        future_to_promise(async move {
+            let mut feedstore = FeedStore::new();
+            feedstore.add(feed);
+            let options = ReplicateOptions { live, sparse: true };
+            replicate_feeds(
+                &addr,
+                feedstore,
+                options,
+                hooks,
+                Rc::default(),
+                Rc::default(),
+            )
+            .await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Resolves to `{ seq, key, value }` for `key`, or `undefined` if it is
+    /// not in the index.
+    pub fn get(&self, key: Vec<u8>) -> Promise {
+        let bee = self.bee.clone();
+        future_to_promise(async move {
+            match bee.get(&key).await.map_err(|err| to_js_error(&err))? {
+                Some(entry) => entry_to_js(&entry),
+                None => Ok(JsValue::UNDEFINED),
+            }
+        })
+    }
+
+    /// Set `key` to `value`. The feed must be writable.
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Promise {
+        let bee = self.bee.clone();
+        future_to_promise(async move {
+            bee.put(&key, &value)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Remove `key`. Resolves to whether it was in the index. The feed must
+    /// be writable.
+    pub fn del(&self, key: Vec<u8>) -> Promise {
+        let bee = self.bee.clone();
+        future_to_promise(async move {
+            let removed = bee.del(&key).await.map_err(|err| to_js_error(&err))?;
+            Ok(JsValue::from_bool(removed))
+        })
+    }
+
+    /// Resolves to an array of `{ seq, key, value }` in key order.
+    ///
+    /// `options` must set `limit`, the most entries to return. They may
+    /// bound the keys with `gt`, `gte`, `lt` and `lte`, and set `reverse`
+    /// to iterate in descending order. To read the next page, pass the last
+    /// key returned as `gt`, or as `lt` when reversed.
+    pub fn range(&self, options: JsValue) -> Promise {
+        let bee = self.bee.clone();
+        future_to_promise(async move {
+            let limit = Reflect::get(&options, &"limit".into())
+                .ok()
+                .and_then(|limit| limit.as_f64())
+                .ok_or_else(|| js_sys::Error::new("range needs a limit"))?;
+            let limit = js_index(limit, "limit")?;
+            let options = RangeOptions {
+                gt: range_bound(&options, "gt")?,
+                gte: range_bound(&options, "gte")?,
+                lt: range_bound(&options, "lt")?,
+                lte: range_bound(&options, "lte")?,
+                reverse: Reflect::get(&options, &"reverse".into())?.is_truthy(),
+                limit: usize::try_from(limit).unwrap_or(usize::MAX),
+            };
+            let entries = bee.range(&options).await.map_err(|err| to_js_error(&err))?;
+            let result = Array::new();
+            for entry in &entries {
+                result.push(&entry_to_js(entry)?);
+            }
+            Ok(result.into())
+        })
+    }
+}
+
+/// A merged log over the feeds of several writers, as handed out to
+/// JavaScript.
+#[wasm_bindgen]
+pub struct AutobaseHandle {
+    autobase: Rc<RefCell<Autobase>>,
+    resolver: RefCell<KeyResolver>,
+    auth: RefCell<PeerAuth>,
+}
+
+impl AutobaseHandle {
+    pub fn new(autobase: Autobase) -> Self {
+        Self {
+            autobase: Rc::new(RefCell::new(autobase)),
+            resolver: RefCell::new(KeyResolver::None),
+            auth: RefCell::new(PeerAuth::Any),
+        }
+    }
+}
+
+/// Convert a `ViewEntry` to `{ writer, seq, value }`.
+fn view_entry_to_js(entry: &ViewEntry) -> Result<JsValue, JsValue> {
+    let result = Object::new();
+    Reflect::set(&result, &"writer".into(), &hex::encode(entry.writer).into())?;
+    Reflect::set(&result, &"seq".into(), &(entry.seq as f64).into())?;
+    let value = Uint8Array::from(&entry.value[..]);
+    Reflect::set(&result, &"value".into(), &value)?;
+    Ok(result.into())
+}
+
+#[wasm_bindgen]
+impl AutobaseHandle {
+    /// The hex encoded public key of the local writer feed, for the other
+    /// writers to add.
+    pub fn local_key(&self) -> String {
+        self.autobase.borrow().local().public_key()
+    }
+
+    /// Add the writer with the hex encoded public key `key`. Its feed is
+    /// replicated on connections opened afterwards.
+    pub fn add_writer(&self, key: String) -> Promise {
+        let autobase = self.autobase.clone();
+        future_to_promise(async move {
+            let key = parse_keys(&[JsValue::from(key)])?[0];
+            let feed = hypercore::open_feed_by_key(key)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            autobase.borrow_mut().add_writer(feed);
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Add the writers the remote announces if their public key is one of
+    /// the hex encoded `keys`. Applies to connections opened afterwards.
+    pub fn set_writer_keys(&self, keys: Box<[JsValue]>) -> Result<(), JsValue> {
+        let keys = parse_keys(&keys)?;
+        self.resolver.replace(KeyResolver::Keys(keys));
+        Ok(())
+    }
+
+    /// Decide which feeds the remote announces are added as writers. The
+    /// callback gets the hex encoded discovery key and returns the hex
+    /// encoded public key, a `Promise` of it, or `null` to ignore the feed.
+    /// Applies to connections opened afterwards.
+    pub fn set_writer_resolver(&self, callback: Function) {
+        self.resolver.replace(KeyResolver::Callback(callback));
+    }
+
+    /// Only replicate with peers whose handshake public key is one of the
+    /// hex encoded `keys`, see `FeedHandle.set_peer_allowlist`.
+    pub fn set_peer_allowlist(&self, keys: Box<[JsValue]>) -> Result<(), JsValue> {
+        self.auth.replace(PeerAuth::allowlist(&keys)?);
+        Ok(())
+    }
+
+    /// Decide on every handshake whether to replicate with a peer, see
+    /// `FeedHandle.set_peer_authorizer`.
+    pub fn set_peer_authorizer(&self, callback: Function) {
+        self.auth.replace(PeerAuth::Callback(callback));
+    }
+
+    /// Replicate the feeds of all writers with the peer at the websocket
+    /// address `addr`.
+    pub fn replicate(&self, addr: String, live: bool) -> Promise {
+        let autobase = self.autobase.borrow().clone();
+        let hooks = ReplicateHooks {
+            auth: self.auth.borrow().clone(),
+            resolver: self.resolver.borrow().clone(),
+        };
+        future_to_promise(async move {
+            let options = ReplicateOptions {
+                live,
+                sparse: false,
+            };
+            replicate_feeds(
+                &addr,
+                autobase.writers().clone(),
+                options,
+                hooks,
+                Rc::default(),
+                Rc::default(),
+            )
+            .await?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Append `value` to the local writer feed.
+    pub fn append(&self, value: Vec<u8>) -> Promise {
+        let autobase = self.autobase.borrow().clone();
+        future_to_promise(async move {
+            autobase
+                .append(value)
+                .await
+                .map_err(|err| to_js_error(&err))?;
+            Ok(JsValue::UNDEFINED)
+        })
+    }
+
+    /// Resolves to the entries of all writers in causal order, as an array
+    /// of `{ writer, seq, value }` with the hex encoded writer key. Entries
+    /// that depend on blocks not downloaded yet are left out.
+    pub fn view(&self) -> Promise {
+        let autobase = self.autobase.borrow().clone();
+        future_to_promise(async move {
+            let entries = autobase.view().await.map_err(|err| to_js_error(&err))?;
+            let result = Array::new();
+            for entry in &entries {
+                result.push(&view_entry_to_js(entry)?);
+            }
+            Ok(result.into())
+        })
+    }
+}
@@ -0,0 +1,64 @@
+//! The header block at index 0 that describes the content of a feed.
+//!
+//! The header is encoded like the DEP-0007 `Header` protobuf message, so
+//! headers of feeds written by Node.js hyperdrive or hyperbee can be read.
+//! Field 3 holds the schema name. Hypertrie calls it `subtype`, and
+//! hyperdrive sets it to `hyperdrive`.
+//!
+//! ```protobuf
+//! message Header {
+//!   required string type = 1;
+//!   optional bytes metadata = 2;
+//!   optional string schema = 3;
+//! }
+//! ```
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::proto::{read_field, write_bytes};
+
+const TYPE_FIELD: u64 = 1;
+const METADATA_FIELD: u64 = 2;
+const SCHEMA_FIELD: u64 = 3;
+
+/// The typed header of a feed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct FeedHeader {
+    /// What the feed contains, e.g. `hyperbee`.
+    pub content_type: String,
+    /// The name of the schema the blocks follow.
+    pub schema: Option<String>,
+    /// Application specific data.
+    pub metadata: Option<Vec<u8>>,
+}
+
+impl FeedHeader {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        write_bytes(&mut bytes, TYPE_FIELD, self.content_type.as_bytes());
+        if let Some(metadata) = &self.metadata {
+            write_bytes(&mut bytes, METADATA_FIELD, metadata);
+        }
+        if let Some(schema) = &self.schema {
+            write_bytes(&mut bytes, SCHEMA_FIELD, schema.as_bytes());
+        }
+        bytes
+    }
+
+    pub fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let mut content_type = None;
+        let mut header = FeedHeader::default();
+        while !bytes.is_empty() {
+            let (field, value) = read_field(&mut bytes).context("Invalid header")?;
+            match field {
+                TYPE_FIELD => content_type = Some(value.as_string()?),
+                METADATA_FIELD => header.metadata = Some(value.as_bytes()?.to_vec()),
+                SCHEMA_FIELD => header.schema = Some(value.as_string()?),
+                // Unknown fields are skipped, as protobuf does.
+                _ => {}
+            }
+        }
+        header.content_type = content_type.ok_or_else(|| anyhow!("Header has no type"))?;
+        Ok(header)
+    }
+}
@@ -1,22 +1,40 @@
-use futures::channel::mpsc::UnboundedSender as Sender;
+use futures::channel::mpsc::{self, UnboundedReceiver, UnboundedSender as Sender};
+use futures::channel::oneshot;
+use futures::future::{self, Either, FutureExt};
 use futures::lock::Mutex;
 use futures::sink::SinkExt;
-use futures::stream::StreamExt;
-use hypercore::{Feed, Node, Proof, PublicKey, Signature};
+use futures::stream::{self, StreamExt};
+use hypercore::{Feed, Node, Proof, PublicKey, Signature, Store};
+use hypercore_protocol::schema::Options as ProtocolOptions;
 use hypercore_protocol::schema::*;
-use hypercore_protocol::{discovery_key, Channel, Duplex, Event, Message, Protocol};
+use hypercore_protocol::{
+    discovery_key, Channel, Duplex, Event, Message, Protocol, ProtocolBuilder,
+};
 use log::*;
 use pretty_hash::fmt as pretty_fmt;
 use random_access_storage::RandomAccess;
-use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::convert::{TryFrom, TryInto};
 use std::fmt::Debug;
-use std::io;
+use std::ops::Range;
+use std::rc::Rc;
 use std::sync::Arc;
+use wasm_bindgen::JsValue;
 use wasm_bindgen_futures::spawn_local;
 
-use crate::persistence::{RandomAccessProxy, WasmStorage};
-use crate::ws::{ReadHalf, WriteHalf};
+use crate::archive;
+use crate::auth::PeerAuth;
+use crate::download::{DownloadId, PeerId, PeerStats, Scheduler};
+use crate::extension::Extensions;
+use crate::header::FeedHeader;
+use crate::persistence::{
+    key_namespace, namespaced_id, open_store, sleep, storage_failures, store_id, stored_len,
+    FeedStorage, ProxyOptions, StorageError, WasmStorage, STORE_IDS,
+};
+use crate::resolver::KeyResolver;
+use crate::tree::{children, depth, first_block, full_roots, leaf_hash, TreeReader};
+use crate::ws::{ReadHalf, WebsocketStream, WriteHalf};
 use crate::AppEvent;
 
 fn parse_key_from_string(key: &str) -> anyhow::Result<[u8; 32]> {
@@ -27,44 +45,223 @@ fn parse_key_from_string(key: &str) -> anyhow::Result<[u8; 32]> {
     Ok(key)
 }
 
+/// Open the browser-side feed for a hex encoded public key.
+pub async fn open_feed(key: impl AsRef<str>) -> anyhow::Result<FeedWrapper<FeedStorage>> {
+    open_feed_by_key(parse_key_from_string(key.as_ref())?).await
+}
+
+thread_local! {
+    /// The open feeds, by namespace. Every handle and connection shares one
+    /// `Feed` per set of stores, as separate instances would overwrite each
+    /// other's tree and bitfield. A feed is closed once nothing but this
+    /// registry holds it, e.g. after its JS handles were freed.
+    static OPEN_FEEDS: RefCell<HashMap<String, FeedWrapper<FeedStorage>>> =
+        RefCell::new(HashMap::new());
+}
+
+fn opened_feed(namespace: &str) -> Option<FeedWrapper<FeedStorage>> {
+    OPEN_FEEDS.with(|feeds| {
+        let mut feeds = feeds.borrow_mut();
+        feeds.retain(|_, feed| Arc::strong_count(&feed.feed) > 1);
+        feeds.get(namespace).cloned()
+    })
+}
+
+/// Register a newly opened feed. If the same stores were opened meanwhile,
+/// the feed opened first is returned instead.
+fn register_feed(feed: FeedWrapper<FeedStorage>) -> FeedWrapper<FeedStorage> {
+    OPEN_FEEDS.with(|feeds| {
+        feeds
+            .borrow_mut()
+            .entry(feed.namespace.clone())
+            .or_insert(feed)
+            .clone()
+    })
+}
+
+/// Whether the feed with public key `key` is open.
+pub fn is_open(key: &[u8; 32]) -> bool {
+    opened_feed(&key_namespace(key)).is_some()
+}
+
+/// Open the feed with public key `key`. Its stores are kept apart from
+/// other feeds under the hex encoded discovery key.
+pub async fn open_feed_by_key(key: [u8; 32]) -> anyhow::Result<FeedWrapper<FeedStorage>> {
+    let namespace = key_namespace(&key);
+    if let Some(feed) = opened_feed(&namespace) {
+        return Ok(feed);
+    }
+    let storage = WasmStorage::new_proxy_in(&namespace, ProxyOptions::default()).await?;
+    let public_key = PublicKey::from_bytes(&key)?;
+    let feed = Feed::builder(public_key, storage).build().await?;
+    Ok(register_feed(FeedWrapper::from_storage_feed(
+        feed, &namespace,
+    )))
+}
+
+/// Open a feed we write to, with its stores kept apart under `namespace`.
+/// A keypair is generated and stored when the feed is first opened.
+pub async fn open_writable_feed(namespace: &str) -> anyhow::Result<FeedWrapper<FeedStorage>> {
+    if let Some(feed) = opened_feed(namespace) {
+        return Ok(feed);
+    }
+    let storage = WasmStorage::new_proxy_in(namespace, ProxyOptions::default()).await?;
+    let feed = Feed::with_storage(storage).await?;
+    Ok(register_feed(FeedWrapper::from_storage_feed(
+        feed, namespace,
+    )))
+}
+
+/// Import an archive created by `FeedWrapper::export` and open the feed.
+/// Fails if the feed is open, as its stores are replaced.
+pub async fn import_feed(archive: &archive::Archive) -> anyhow::Result<FeedWrapper<FeedStorage>> {
+    let key = parse_key_from_string(&archive.public_key()?)?;
+    if is_open(&key) {
+        anyhow::bail!("Feed {} is open and can't be replaced", hex::encode(key));
+    }
+    archive::import(archive, &key_namespace(&key)).await?;
+    open_feed_by_key(key).await
+}
+
+/// Open a websocket to `addr` and start a hypercore-protocol stream on it.
+///
+/// The handshake uses a new keypair for every connection, as the pinned
+/// `ProtocolBuilder` can't take a static one.
+pub async fn connect(addr: &str) -> anyhow::Result<Protocol<Duplex<ReadHalf, WriteHalf>>> {
+    let websocket = WebsocketStream::connect(addr).await?;
+    let (reader, writer) = websocket.split();
+    Ok(ProtocolBuilder::new(true).connect_rw(reader, writer))
+}
+
+/// Options for replicating the feeds of a `FeedStore`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ReplicateOptions {
+    /// Keep downloading new blocks as the remote appends them, instead of
+    /// stopping after the initial sync.
+    pub live: bool,
+    /// Only download the blocks selected with `FeedWrapper::download`
+    /// instead of the whole feed.
+    pub sparse: bool,
+}
+
+/// App-provided decisions taken during replication.
+#[derive(Debug, Clone, Default)]
+pub struct ReplicateHooks {
+    /// Which remote peers may replicate with us.
+    pub auth: PeerAuth,
+    /// Which feeds the remote opens that we don't have open yet are opened.
+    pub resolver: KeyResolver,
+}
+
+/// Run `replicate` in the background. A failure is sent as `AppEvent::Error`.
+/// Feeds sent to the returned sender are opened on the same connection.
+pub fn spawn_replicate(
+    protocol: Protocol<Duplex<ReadHalf, WriteHalf>>,
+    feedstore: FeedStore,
+    options: ReplicateOptions,
+    hooks: ReplicateHooks,
+    app_tx: Sender<AppEvent>,
+) -> Sender<FeedWrapper<FeedStorage>> {
+    let (added_tx, added_rx) = mpsc::unbounded();
+    spawn_local(async move {
+        info!("call hypercore::replicate");
+        let mut error_tx = app_tx.clone();
+        if let Err(err) = replicate(protocol, feedstore, options, hooks, app_tx, added_rx).await {
+            error!("replication failed: {:#}", err);
+            error_tx.send(AppEvent::Error(err)).await.ok();
+        }
+    });
+    added_tx
+}
+
+/// Replicate the feeds of `feedstore`, and the feeds received on `added`
+/// while running, with the remote of `protocol`.
 pub async fn replicate(
     mut protocol: Protocol<Duplex<ReadHalf, WriteHalf>>,
-    key: impl AsRef<str>,
-    app_tx: Sender<AppEvent>,
+    mut feedstore: FeedStore,
+    options: ReplicateOptions,
+    hooks: ReplicateHooks,
+    mut app_tx: Sender<AppEvent>,
+    added: UnboundedReceiver<FeedWrapper<FeedStorage>>,
 ) -> anyhow::Result<()> {
-    let mut feedstore = FeedStore::new();
-    let storage = WasmStorage::new_proxy().await.unwrap();
-    let key = parse_key_from_string(key.as_ref())?;
-    let public_key = PublicKey::from_bytes(&key).unwrap();
-    let feed = Feed::builder(public_key, storage).build().await.unwrap();
-    let feed_wrapper = FeedWrapper::from_proxy_feed(feed);
-    feedstore.add(feed_wrapper);
-    let feedstore = Arc::new(feedstore);
-
-    while let Some(event) = protocol.next().await {
-        let event = event.expect("Protocol error");
+    let mut handshake_done = false;
+    let mut remote_public_key = vec![];
+    let mut added = Some(added);
+    loop {
+        let input = match added.as_mut() {
+            Some(added) => match future::select(protocol.next(), added.next()).await {
+                Either::Left((event, _)) => Either::Left(event),
+                Either::Right((feed, _)) => Either::Right(feed),
+            },
+            None => Either::Left(protocol.next().await),
+        };
+        let event = match input {
+            Either::Left(Some(event)) => event,
+            Either::Left(None) => break,
+            Either::Right(Some(feed)) => {
+                if feedstore.get(&feed.discovery_key).is_none() {
+                    debug!("open added feed {}", feed.public_key());
+                    let key = feed.key;
+                    feedstore.add(feed);
+                    protocol
+                        .open(key)
+                        .await
+                        .map_err(|err| ReplicationError::from_io(&err, handshake_done))?;
+                }
+                continue;
+            }
+            Either::Right(None) => {
+                added = None;
+                continue;
+            }
+        };
+        let event = event.map_err(|err| ReplicationError::from_io(&err, handshake_done))?;
         match event {
-            Event::Handshake(_remote_public_key) => {
+            Event::Handshake(key) => {
                 debug!("received handshake from remote");
+                handshake_done = true;
+                if !hooks.auth.authorize(&key).await {
+                    // Dropping the protocol closes the websocket.
+                    return Err(ReplicationError::Unauthorized {
+                        remote_public_key: hex::encode(&key),
+                    }
+                    .into());
+                }
+                remote_public_key = key;
             }
             Event::DiscoveryKey(discovery_key) => {
-                if let Some(feed) = feedstore.get(&discovery_key) {
-                    debug!(
-                        "open discovery_key: {}",
-                        pretty_fmt(&discovery_key).unwrap()
-                    );
-                    protocol.open(feed.key).await.unwrap();
+                let key = match feedstore.get(&discovery_key) {
+                    Some(feed) => Some(feed.key),
+                    None => match hooks.resolver.resolve(&discovery_key).await {
+                        Some(key) => match open_feed_by_key(key).await {
+                            Ok(feed) => {
+                                info!("opened related feed {}", feed.public_key());
+                                feedstore.add(feed.clone());
+                                app_tx.send(AppEvent::Opened(feed)).await.ok();
+                                Some(key)
+                            }
+                            Err(err) => {
+                                error!("failed to open related feed: {:#}", err);
+                                None
+                            }
+                        },
+                        None => None,
+                    },
+                };
+                if let Some(key) = key {
+                    debug!("open discovery_key: {}", fmt_key(&discovery_key));
+                    protocol
+                        .open(key)
+                        .await
+                        .map_err(|err| ReplicationError::from_io(&err, handshake_done))?;
                 } else {
-                    debug!(
-                        "unknown discovery_key: {}",
-                        pretty_fmt(&discovery_key).unwrap()
-                    );
+                    debug!("unknown discovery_key: {}", fmt_key(&discovery_key));
                 }
             }
             Event::Channel(channel) => {
                 if let Some(feed) = feedstore.get(channel.discovery_key()) {
                     let mut app_tx = app_tx.clone();
-                    feed.on_peer(channel, &mut app_tx);
+                    feed.on_peer(channel, options, remote_public_key.clone(), &mut app_tx);
                     // let feed = feed.clone();
                     // let mut app_tx = app_tx.clone();
                     // feed.on_open(&mut channel).await.unwrap();
@@ -81,69 +278,706 @@ pub async fn replicate(
             _ => {}
         }
     }
+    // The stream ending after the handshake is a regular close, e.g. by the
+    // remote after a non-live sync.
+    if !handshake_done {
+        return Err(ReplicationError::TransportClosed {
+            message: "Connection closed before the handshake".to_string(),
+        }
+        .into());
+    }
     Ok(())
 }
 
+/// Shorten a key for logging.
+fn fmt_key(key: &[u8]) -> String {
+    pretty_fmt(key).unwrap_or_else(|_| hex::encode(key))
+}
+
+/// Errors of the replication stream with a remote peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplicationError {
+    /// The Noise handshake with the remote failed.
+    Handshake { message: String },
+    /// The websocket was closed or broke down.
+    TransportClosed { message: String },
+    /// The remote sent a message that could not be decoded.
+    Decode { message: String },
+    /// The remote's public key was rejected by the `PeerAuth`.
+    Unauthorized { remote_public_key: String },
+    /// The remote queued more requests than `MAX_QUEUED_UPLOADS`.
+    TooManyRequests { limit: usize },
+}
+
+impl ReplicationError {
+    /// Classify an error of the protocol stream.
+    pub fn from_io(err: &std::io::Error, handshake_done: bool) -> Self {
+        use std::io::ErrorKind::*;
+        let message = err.to_string();
+        match err.kind() {
+            InvalidData | InvalidInput if handshake_done => ReplicationError::Decode { message },
+            ConnectionReset | ConnectionAborted | BrokenPipe | NotConnected | UnexpectedEof => {
+                ReplicationError::TransportClosed { message }
+            }
+            _ if !handshake_done => ReplicationError::Handshake { message },
+            _ => ReplicationError::TransportClosed { message },
+        }
+    }
+
+    /// The name of the error as exposed to JavaScript.
+    pub fn name(&self) -> &str {
+        match self {
+            ReplicationError::Handshake { .. } => "HandshakeError",
+            ReplicationError::TransportClosed { .. } => "TransportClosedError",
+            ReplicationError::Decode { .. } => "DecodeError",
+            ReplicationError::Unauthorized { .. } => "NotAllowedError",
+            ReplicationError::TooManyRequests { .. } => "TooManyRequestsError",
+        }
+    }
+}
+
+impl std::fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicationError::Handshake { message } => write!(f, "Handshake failed: {}", message),
+            ReplicationError::TransportClosed { message } => {
+                write!(f, "Transport closed: {}", message)
+            }
+            ReplicationError::Decode { message } => {
+                write!(f, "Failed to decode message: {}", message)
+            }
+            ReplicationError::Unauthorized { remote_public_key } => {
+                write!(f, "Peer {} is not authorized", remote_public_key)
+            }
+            ReplicationError::TooManyRequests { limit } => {
+                write!(f, "Peer queued more than {} requests", limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ReplicationError {}
+
+impl From<ReplicationError> for JsValue {
+    fn from(err: ReplicationError) -> Self {
+        let js_err = js_sys::Error::new(&err.to_string());
+        js_err.set_name(err.name());
+        js_err.into()
+    }
+}
+
 /// A Feed is a single unit of replication, an append-only log.
 #[derive(Debug, Clone)]
-struct FeedWrapper<T>
+pub struct FeedWrapper<T>
 where
     T: RandomAccess<Error = Box<dyn std::error::Error + Send + Sync>> + Debug + Send,
 {
     discovery_key: [u8; 32],
     key: [u8; 32],
     feed: Arc<Mutex<Feed<T>>>,
+    scheduler: Arc<Mutex<Scheduler>>,
+    extensions: Arc<Mutex<Extensions>>,
+    /// Prefix of the feed's store ids, see `key_namespace`.
+    namespace: String,
 }
 
-impl FeedWrapper<RandomAccessProxy> {
-    pub fn from_proxy_feed(feed: Feed<RandomAccessProxy>) -> Self {
+impl FeedWrapper<FeedStorage> {
+    pub fn from_storage_feed(feed: Feed<FeedStorage>, namespace: &str) -> Self {
         let key = feed.public_key().to_bytes();
         FeedWrapper {
             key,
             discovery_key: discovery_key(&key),
             feed: Arc::new(Mutex::new(feed)),
+            scheduler: Arc::new(Mutex::new(Scheduler::default())),
+            extensions: Arc::new(Mutex::new(Extensions::default())),
+            namespace: namespace.to_string(),
         }
     }
 
-    pub fn on_peer(&self, mut channel: Channel, app_tx: &mut Sender<AppEvent>) {
-        let mut feed = self.feed.clone();
+    /// The hex encoded public key of the feed.
+    pub fn public_key(&self) -> String {
+        hex::encode(&self.key)
+    }
+
+    /// The public key of the feed.
+    pub fn key(&self) -> [u8; 32] {
+        self.key
+    }
+
+    pub fn discovery_key(&self) -> [u8; 32] {
+        self.discovery_key
+    }
+
+    /// The backend id of one of the feed's stores.
+    fn store_id(&self, store: &Store) -> String {
+        namespaced_id(&self.namespace, store_id(store))
+    }
+
+    pub fn on_peer(
+        &self,
+        mut channel: Channel,
+        options: ReplicateOptions,
+        remote_public_key: Vec<u8>,
+        app_tx: &mut Sender<AppEvent>,
+    ) {
+        let feed = self.clone();
         let mut app_tx = app_tx.clone();
         spawn_local(async move {
             let msg = Want {
                 start: 0,
                 length: None,
             };
-            channel.send(Message::Want(msg)).await.unwrap();
-            let mut state = FeedState::default();
-            while let Some(message) = channel.next().await {
-                let result =
-                    on_message(&mut feed, &mut state, &mut channel, message, &mut app_tx).await;
+            if let Err(err) = channel.send(Message::Want(msg)).await {
+                let err = ReplicationError::from_io(&err, true);
+                app_tx.send(AppEvent::Error(err.into())).await.ok();
+                return;
+            }
+            let messages = match channel.take_receiver() {
+                Some(messages) => messages,
+                None => {
+                    let err = anyhow::anyhow!(
+                        "Messages of channel {} are already taken",
+                        fmt_key(channel.discovery_key())
+                    );
+                    app_tx.send(AppEvent::Error(err)).await.ok();
+                    return;
+                }
+            };
+            // Wake up when the scheduler has new blocks or the feed was
+            // appended to, as well as on messages. The length is read under
+            // the feed lock, so that no append is missed.
+            let (peer, wake, announced) = {
+                let local = feed.feed.lock().await;
+                let mut scheduler = feed.scheduler.lock().await;
+                let (peer, wake) = scheduler.add_peer(remote_public_key, js_sys::Date::now());
+                (peer, wake, local.len())
+            };
+            let mut state = FeedState {
+                peer,
+                announced,
+                live: options.live,
+                sparse: options.sparse,
+                ..Default::default()
+            };
+            let (names, outgoing) = feed.extensions.lock().await.add_peer(peer);
+            state.local_extensions = names;
+            if !state.local_extensions.is_empty() {
+                let msg = ProtocolOptions {
+                    extensions: state.local_extensions.clone(),
+                    ack: None,
+                };
+                channel.send(Message::Options(msg)).await.ok();
+            }
+            let mut inputs = stream::select(
+                stream::select(
+                    messages.map(PeerInput::Message),
+                    wake.map(|_| PeerInput::Wake),
+                ),
+                outgoing.map(|(name, payload)| PeerInput::Extension(name, payload)),
+            );
+            loop {
+                // Queued uploads are served once no other input is ready, so
+                // that a `Cancel` or `Unwant` can still remove them.
+                let input = if state.uploads.is_empty() {
+                    inputs.next().await
+                } else {
+                    match inputs.next().now_or_never() {
+                        Some(input) => input,
+                        None => Some(PeerInput::Upload),
+                    }
+                };
+                let input = match input {
+                    Some(input) => input,
+                    None => break,
+                };
+                let result = match input {
+                    PeerInput::Message(message) => {
+                        on_message(&feed, &mut state, &mut channel, message, &mut app_tx).await
+                    }
+                    PeerInput::Wake => on_wake(&feed, &mut state, &mut channel, &mut app_tx).await,
+                    PeerInput::Upload => on_upload(&feed, &mut state, &mut channel).await,
+                    PeerInput::Extension(name, payload) => {
+                        send_extension(&state, &mut channel, &name, payload).await
+                    }
+                };
                 if let Err(e) = result {
+                    if let Some(invalid) = e.downcast_ref::<InvalidData>() {
+                        // Drop the peer, the scheduler hands its blocks to
+                        // the others.
+                        warn!("closing channel: {}", invalid);
+                        let close = Close {
+                            discovery_key: None,
+                        };
+                        channel.send(Message::Close(close)).await.ok();
+                        let event = AppEvent::InvalidData {
+                            index: invalid.index,
+                            reason: invalid.reason.clone(),
+                        };
+                        app_tx.send(event).await.ok();
+                        break;
+                    }
                     error!("protocol error: {}", e);
+                    app_tx.send(AppEvent::Error(e)).await.ok();
+                    break;
+                }
+                if state.closed {
+                    debug!("remote closed the channel");
                     break;
                 }
             }
+            feed.scheduler.lock().await.remove_peer(peer);
+            feed.extensions.lock().await.remove_peer(peer);
         });
     }
+
+    /// Read all blocks and join them into a string.
+    pub async fn read_text(&self) -> anyhow::Result<String> {
+        let mut feed = self.feed.lock().await;
+        let mut full_text = String::new();
+        for i in 0..feed.len() {
+            let block = feed
+                .get(i)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Block {} missing after download", i))?;
+            full_text.push_str(&String::from_utf8(block)?);
+        }
+        Ok(full_text)
+    }
+
+    /// Select `ranges` of blocks for download. The receiver resolves once
+    /// they are all stored locally.
+    pub async fn download(
+        &self,
+        ranges: Vec<Range<u64>>,
+    ) -> (DownloadId, oneshot::Receiver<anyhow::Result<()>>) {
+        let mut feed = self.feed.lock().await;
+        let mut scheduler = self.scheduler.lock().await;
+        let (id, done) = scheduler.add(ranges);
+        scheduler.update(&mut |index: u64| feed.has(index));
+        (id, done)
+    }
+
+    /// Stop downloading a selection. Its receiver fails with `Canceled`.
+    pub async fn cancel_download(&self, id: DownloadId) -> bool {
+        self.scheduler.lock().await.cancel(id)
+    }
+
+    /// Read the header from block 0. Waits for it to be downloaded like
+    /// `get`. Returns `None` if the feed is empty or the block is missing.
+    pub async fn header(
+        &self,
+        wait: bool,
+        timeout_ms: Option<u32>,
+    ) -> anyhow::Result<Option<FeedHeader>> {
+        match self.get(0, wait, timeout_ms).await? {
+            Some(block) => Ok(Some(FeedHeader::decode(&block)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Append the header as block 0 of a writable, empty feed.
+    pub async fn write_header(&self, header: &FeedHeader) -> anyhow::Result<()> {
+        let mut feed = self.feed.lock().await;
+        if feed.len() > 0 {
+            anyhow::bail!("The header must be the first block of the feed");
+        }
+        feed.append(&header.encode()).await?;
+        self.scheduler.lock().await.on_append(feed.len());
+        Ok(())
+    }
+
+    /// Check a locally available header against the expected content type.
+    /// A feed without a local header passes, it is checked once block 0 is
+    /// read with `header`.
+    pub async fn validate_header(&self, content_type: &str) -> anyhow::Result<()> {
+        if let Some(header) = self.header(false, None).await? {
+            if header.content_type != content_type {
+                anyhow::bail!(
+                    "Feed contains {}, expected {}",
+                    header.content_type,
+                    content_type
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the block at `index`.
+    ///
+    /// If the block is not stored locally and `wait` is set, it is selected
+    /// for download and read once a peer delivered it. Fails if it didn't
+    /// arrive within `timeout_ms`, if given.
+    pub async fn get(
+        &self,
+        index: u64,
+        wait: bool,
+        timeout_ms: Option<u32>,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        {
+            let mut feed = self.feed.lock().await;
+            if feed.has(index) || !wait {
+                return Ok(feed.get(index).await?);
+            }
+        }
+        let (id, done) = self.download(vec![index..index + 1]).await;
+        let done = match timeout_ms {
+            None => done.await,
+            Some(ms) => match future::select(done, Box::pin(sleep(ms))).await {
+                Either::Left((done, _)) => done,
+                Either::Right(_) => {
+                    self.cancel_download(id).await;
+                    anyhow::bail!("Timed out waiting for block {}", index);
+                }
+            },
+        };
+        done??;
+        Ok(self.feed.lock().await.get(index).await?)
+    }
+
+    /// Find the block holding byte `byte_offset` of the feed. Returns its
+    /// index and the offset of the byte within it.
+    ///
+    /// The block sizes are summed from the sizes stored in the Merkle tree.
+    /// If a tree node on the way is not stored and `wait` is set, the first
+    /// block below it is downloaded, which stores the node with its proof.
+    pub async fn seek(
+        &self,
+        byte_offset: u64,
+        wait: bool,
+        timeout_ms: Option<u32>,
+    ) -> anyhow::Result<(u64, u64)> {
+        let (len, byte_len) = {
+            let feed = self.feed.lock().await;
+            (feed.len(), feed.byte_len())
+        };
+        if byte_offset >= byte_len {
+            anyhow::bail!(
+                "Byte offset {} is past the end of the feed ({} bytes)",
+                byte_offset,
+                byte_len
+            );
+        }
+        let mut tree = TreeReader::open(&self.store_id(&Store::Tree)).await?;
+        let mut offset = byte_offset;
+        for root in full_roots(2 * len) {
+            let size = self.node_size(&mut tree, root, wait, timeout_ms).await?;
+            if offset >= size {
+                offset -= size;
+                continue;
+            }
+            let mut node = root;
+            while depth(node) > 0 {
+                let (left, right) = children(node);
+                let size = self.node_size(&mut tree, left, wait, timeout_ms).await?;
+                if offset < size {
+                    node = left;
+                } else {
+                    offset -= size;
+                    node = right;
+                }
+            }
+            return Ok((node / 2, offset));
+        }
+        anyhow::bail!("Byte offset {} is not covered by the tree", byte_offset)
+    }
+
+    /// The byte size of the tree node at `index`, fetching it if needed.
+    async fn node_size(
+        &self,
+        tree: &mut TreeReader,
+        index: u64,
+        wait: bool,
+        timeout_ms: Option<u32>,
+    ) -> anyhow::Result<u64> {
+        if let Some(size) = tree.stored_node_size(index).await? {
+            return Ok(size);
+        }
+        if wait {
+            self.get(first_block(index), true, timeout_ms).await?;
+            if let Some(size) = tree.stored_node_size(index).await? {
+                return Ok(size);
+            }
+        }
+        anyhow::bail!("Tree node {} is not stored", index)
+    }
+
+    /// Read `length` bytes starting at byte `byte_offset` of the feed,
+    /// across block boundaries. The range is cut off at the end of the feed.
+    ///
+    /// Like `get`, missing blocks are downloaded if `wait` is set, and the
+    /// read fails if a block doesn't arrive within `timeout_ms`.
+    pub async fn read_bytes(
+        &self,
+        byte_offset: u64,
+        length: u64,
+        wait: bool,
+        timeout_ms: Option<u32>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let length = length.min(self.byte_len().await.saturating_sub(byte_offset));
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        let (first, skip) = self.seek(byte_offset, wait, timeout_ms).await?;
+        let (last, _) = self
+            .seek(byte_offset + length - 1, wait, timeout_ms)
+            .await?;
+        // Select all blocks at once, so they are downloaded in parallel
+        // rather than one by one as they are read.
+        let prefetch = if wait {
+            Some(self.download(vec![first..last + 1]).await.0)
+        } else {
+            None
+        };
+        let read = async {
+            let mut data = Vec::with_capacity(length as usize);
+            let mut skip = skip as usize;
+            for index in first..=last {
+                let block = self
+                    .get(index, wait, timeout_ms)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Block {} is not stored", index))?;
+                let end = block.len().min(skip + (length as usize - data.len()));
+                data.extend_from_slice(&block[skip.min(end)..end]);
+                skip = 0;
+            }
+            Ok::<_, anyhow::Error>(data)
+        };
+        let result = read.await;
+        if let Some(id) = prefetch {
+            self.cancel_download(id).await;
+        }
+        result
+    }
+
+    /// Register an extension for channels opened afterwards. The handler is
+    /// called with the payload and the id of the sending peer.
+    pub async fn register_extension(&self, name: &str, handler: js_sys::Function) {
+        self.extensions.lock().await.register(name, handler);
+    }
+
+    /// Send an extension message to the peer with `peer` id. Returns whether
+    /// the peer is connected.
+    pub async fn send_extension(&self, peer: PeerId, name: &str, payload: Vec<u8>) -> bool {
+        self.extensions.lock().await.send(peer, name, payload)
+    }
+
+    /// Send an extension message to all connected peers. Returns the number
+    /// of peers.
+    pub async fn broadcast_extension(&self, name: &str, payload: &[u8]) -> usize {
+        self.extensions.lock().await.broadcast(name, payload)
+    }
+
+    /// Statistics of the peers currently replicating the feed.
+    pub async fn peers(&self) -> Vec<PeerStats> {
+        self.scheduler.lock().await.peer_stats()
+    }
+
+    /// Number of blocks in the feed.
+    pub async fn len(&self) -> u64 {
+        self.feed.lock().await.len()
+    }
+
+    /// Bytes the stores of the feed currently take up. All stores are
+    /// opened with the feed, so none is counted as empty for not being
+    /// loaded yet. Cleared blocks only count as long as they are followed by
+    /// stored ones, as the holes they leave keep the store's length.
+    pub fn usage(&self) -> u64 {
+        STORE_IDS
+            .iter()
+            .map(|id| stored_len(&namespaced_id(&self.namespace, id)))
+            .sum()
+    }
+
+    /// Number of bytes in the feed.
+    pub async fn byte_len(&self) -> u64 {
+        self.feed.lock().await.byte_len()
+    }
+
+    /// Append a block to a writable feed. Returns its index.
+    pub async fn append(&self, data: &[u8]) -> anyhow::Result<u64> {
+        let mut feed = self.feed.lock().await;
+        let index = feed.len();
+        feed.append(data).await?;
+        self.scheduler.lock().await.on_append(feed.len());
+        Ok(index)
+    }
+
+    /// Remove the downloaded data of blocks `start..end` from storage.
+    ///
+    /// The cleared blocks are deleted from the data store and unset in the
+    /// bitfield, so they will be downloaded again when needed.
+    pub async fn clear(&self, start: u64, end: u64) -> anyhow::Result<()> {
+        let mut feed = self.feed.lock().await;
+        let end = end.min(feed.len());
+        if start >= end {
+            return Ok(());
+        }
+        let mut tree = TreeReader::open(&self.store_id(&Store::Tree)).await?;
+        let range = tree.byte_range(start, end).await?;
+        let mut data = open_store(&self.store_id(&Store::Data)).await?;
+        data.del(range.start, range.end - range.start)
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
+        self.unset_blocks(&mut feed, &[start..end]).await?;
+        debug!("cleared blocks {}..{}", start, end);
+        Ok(())
+    }
+
+    /// Unset `blocks` in the stored bitfield, then reload the feed from its
+    /// stores so that its bitfield matches.
+    ///
+    /// The stores are shared with the feed's own, so the reload sees every
+    /// write the feed made.
+    async fn unset_blocks(
+        &self,
+        feed: &mut Feed<FeedStorage>,
+        blocks: &[Range<u64>],
+    ) -> anyhow::Result<()> {
+        let mut bitfield = open_store(&self.store_id(&Store::Bitfield)).await?;
+        for range in blocks {
+            unset_stored_blocks(&mut bitfield, range.clone()).await?;
+        }
+        let storage = WasmStorage::new_proxy_in(&self.namespace, ProxyOptions::default()).await?;
+        let reloaded = Feed::with_storage(storage).await?;
+        if reloaded.public_key().to_bytes() != self.key {
+            anyhow::bail!("Feed {} has no stored key", self.public_key());
+        }
+        *feed = reloaded;
+        Ok(())
+    }
+
+    /// Check the stored blocks against the tree hashes and the latest
+    /// signature.
+    ///
+    /// With `repair` set, corrupt blocks are unset in the bitfield so they
+    /// are downloaded again on the next replication.
+    pub async fn verify(&self, repair: bool) -> anyhow::Result<VerifyReport> {
+        let mut feed = self.feed.lock().await;
+        let mut tree = TreeReader::open(&self.store_id(&Store::Tree)).await?;
+        let mut report = VerifyReport::default();
+        for index in 0..feed.len() {
+            if !feed.has(index) {
+                continue;
+            }
+            report.checked += 1;
+            // A tree node that can't be read leaves the block unverifiable,
+            // which is reported like a mismatch.
+            let valid = match (feed.get(index).await, tree.node_hash(2 * index).await) {
+                (Ok(Some(data)), Ok(hash)) => leaf_hash(&data) == hash,
+                _ => false,
+            };
+            if !valid {
+                report.corrupt.push(index);
+            }
+        }
+        report.signature_valid = match feed.len().checked_sub(1) {
+            None => true,
+            Some(head) => match feed.signature(head).await {
+                Ok(signature) => feed.verify(head, &signature).await.is_ok(),
+                Err(_) => false,
+            },
+        };
+        if repair && !report.corrupt.is_empty() {
+            let blocks: Vec<Range<u64>> = report
+                .corrupt
+                .iter()
+                .map(|index| *index..*index + 1)
+                .collect();
+            self.unset_blocks(&mut feed, &blocks).await?;
+            info!(
+                "repaired feed, {} corrupt blocks cleared",
+                report.corrupt.len()
+            );
+        }
+        Ok(report)
+    }
+
+    /// Serialize the feed's stores into a portable archive.
+    pub async fn export(&self, include_secret_key: bool) -> anyhow::Result<Vec<u8>> {
+        // Hold the lock so that no block is written while reading the stores.
+        let _feed = self.feed.lock().await;
+        let archive = archive::export(&self.namespace, include_secret_key).await?;
+        Ok(archive.to_bytes())
+    }
 }
 
-struct FeedStore {
-    feeds: HashMap<String, Arc<FeedWrapper<RandomAccessProxy>>>,
+/// Size of the bitfield store header.
+const BITFIELD_HEADER_SIZE: u64 = 32;
+/// A bitfield page holds the bits of 1024 bytes worth of blocks, followed by
+/// the tree and index bits hypercore derives from them.
+const BITFIELD_PAGE_SIZE: u64 = 3328;
+const BITFIELD_BLOCK_BYTES: u64 = 1024;
+
+/// Unset the bits of `blocks` in a bitfield store. Bytes past the end of the
+/// store have no bits set already.
+async fn unset_stored_blocks(bitfield: &mut FeedStorage, blocks: Range<u64>) -> anyhow::Result<()> {
+    let size = bitfield.len().await.map_err(|err| anyhow::anyhow!(err))?;
+    let blocks_per_page = BITFIELD_BLOCK_BYTES * 8;
+    let mut block = blocks.start;
+    while block < blocks.end {
+        let page = block / blocks_per_page;
+        let page_end = blocks.end.min((page + 1) * blocks_per_page);
+        let first_byte = block % blocks_per_page / 8;
+        let last_byte = (page_end - 1) % blocks_per_page / 8;
+        let offset = BITFIELD_HEADER_SIZE + page * BITFIELD_PAGE_SIZE + first_byte;
+        let length = (last_byte + 1 - first_byte).min(size.saturating_sub(offset));
+        if length > 0 {
+            let mut bytes = bitfield
+                .read(offset, length)
+                .await
+                .map_err(|err| anyhow::anyhow!(err))?;
+            for index in block..page_end {
+                let at = (index % blocks_per_page / 8 - first_byte) as usize;
+                if let Some(byte) = bytes.get_mut(at) {
+                    *byte &= !(128 >> (index % 8));
+                }
+            }
+            bitfield
+                .write(offset, &bytes)
+                .await
+                .map_err(|err| anyhow::anyhow!(err))?;
+        }
+        block = page_end;
+    }
+    Ok(())
+}
+
+/// The result of `FeedWrapper::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// Number of locally available blocks that were checked.
+    pub checked: u64,
+    /// Indices of blocks whose data doesn't match the tree, or whose tree
+    /// node could not be read.
+    pub corrupt: Vec<u64>,
+    /// Whether the tree roots match the latest signature.
+    pub signature_valid: bool,
+}
+
+/// The feeds of a replication, by discovery key. Clones share the feeds, so
+/// feeds a running replication resolves show up in every clone.
+#[derive(Debug, Clone, Default)]
+pub struct FeedStore {
+    feeds: Rc<RefCell<HashMap<String, Arc<FeedWrapper<FeedStorage>>>>>,
 }
 impl FeedStore {
     pub fn new() -> Self {
-        let feeds = HashMap::new();
-        Self { feeds }
+        Self::default()
     }
 
-    pub fn add(&mut self, feed: FeedWrapper<RandomAccessProxy>) {
+    pub fn add(&mut self, feed: FeedWrapper<FeedStorage>) {
         let hdkey = hex::encode(&feed.discovery_key);
-        self.feeds.insert(hdkey, Arc::new(feed));
+        self.feeds.borrow_mut().insert(hdkey, Arc::new(feed));
     }
 
-    pub fn get(&self, discovery_key: &[u8]) -> Option<&Arc<FeedWrapper<RandomAccessProxy>>> {
+    pub fn get(&self, discovery_key: &[u8]) -> Option<Arc<FeedWrapper<FeedStorage>>> {
         let hdkey = hex::encode(discovery_key);
-        self.feeds.get(&hdkey)
+        self.feeds.borrow().get(&hdkey).cloned()
+    }
+
+    /// All feeds, in no particular order.
+    pub fn feeds(&self) -> Vec<Arc<FeedWrapper<FeedStorage>>> {
+        self.feeds.borrow().values().cloned().collect()
     }
 }
 
@@ -163,23 +997,92 @@ impl FeedStore {
 //             state: Mutex::new(FeedState::default()),
 //         }
 //     }
+/// Input of a replication task: a message from the peer, a nudge that
+/// new blocks were selected, a turn to serve a queued upload, or an
+/// extension message to send.
+enum PeerInput {
+    Message(Message),
+    Wake,
+    Upload,
+    Extension(String, Vec<u8>),
+}
+
+/// Number of requests of a peer that are queued before it is disconnected.
+const MAX_QUEUED_UPLOADS: usize = 256;
+
+/// The end of the range `start..start + length` of a message, `default`
+/// without a length. `None` if the remote sent a range that overflows.
+fn message_end(start: u64, length: Option<u64>, default: u64) -> Option<u64> {
+    match length {
+        Some(length) => start.checked_add(length),
+        None => Some(default),
+    }
+}
+
 async fn on_message(
-    feed: &mut Arc<Mutex<Feed<RandomAccessProxy>>>,
+    feed: &FeedWrapper<FeedStorage>,
     state: &mut FeedState,
     channel: &mut Channel,
     message: Message,
     app_tx: &mut Sender<AppEvent>,
-) -> io::Result<()> {
+) -> anyhow::Result<()> {
     // debug!("receive message: {:?}", message);
     match message {
-        Message::Have(message) => on_have(feed, state, channel, message).await,
+        Message::Have(message) => on_have(feed, state, channel, message, app_tx).await,
         Message::Data(message) => on_data(feed, state, channel, message, app_tx).await,
+        Message::Unhave(message) => on_unhave(feed, state, channel, message, app_tx).await,
+        Message::Want(message) => on_want(feed, channel, message).await,
+        Message::Unwant(message) => {
+            let end = match message_end(message.start, message.length, u64::MAX) {
+                Some(end) => end,
+                None => return Ok(()),
+            };
+            state
+                .uploads
+                .retain(|request| request.index < message.start || request.index >= end);
+            Ok(())
+        }
+        Message::Request(message) => {
+            // Requests are answered one at a time, a peer that doesn't wait
+            // for the data is disconnected.
+            if state.uploads.len() >= MAX_QUEUED_UPLOADS {
+                let limit = MAX_QUEUED_UPLOADS;
+                return Err(ReplicationError::TooManyRequests { limit }.into());
+            }
+            state.uploads.push_back(message);
+            Ok(())
+        }
+        Message::Cancel(message) => {
+            state
+                .uploads
+                .retain(|request| request.index != message.index);
+            Ok(())
+        }
+        Message::Status(message) => on_status(feed, state, channel, message, app_tx).await,
+        Message::Close(_) => {
+            state.closed = true;
+            Ok(())
+        }
+        Message::Options(message) => {
+            state.remote_extensions = message.extensions;
+            Ok(())
+        }
+        Message::Extension(message) => {
+            match state.remote_extensions.get(message.id as usize) {
+                Some(name) => {
+                    let extensions = feed.extensions.lock().await;
+                    extensions.on_message(name, state.peer, &message.message);
+                }
+                None => debug!("receive unknown extension {}", message.id),
+            }
+            Ok(())
+        }
         _ => Ok(()),
     }
 }
 
 // async fn on_open(
-//     feed: &mut Arc<Mutex<Feed<RandomAccessProxy>>>,
+//     feed: &mut Arc<Mutex<Feed<FeedStorage>>>,
 //     state: Mutex<FeedState>,
 //     channel: &mut Channel,
 // ) -> io::Result<()> {
@@ -194,46 +1097,262 @@ async fn on_message(
 //     channel.want(msg).await
 // }
 
+async fn on_wake(
+    feed: &FeedWrapper<FeedStorage>,
+    state: &mut FeedState,
+    channel: &mut Channel,
+    app_tx: &mut Sender<AppEvent>,
+) -> anyhow::Result<()> {
+    let (mut feed, mut scheduler) = (feed.feed.lock().await, feed.scheduler.lock().await);
+    // Blocks appended after the channel opened are announced, the remote
+    // only learned about the earlier ones in the reply to its `Want`.
+    let appended = scheduler.appended();
+    if let Some(have) = appended_have(state.announced, appended) {
+        debug!(
+            "announce appended blocks: {}..{}",
+            state.announced, appended
+        );
+        state.announced = appended;
+        channel
+            .send(Message::Have(have))
+            .await
+            .map_err(|err| ReplicationError::from_io(&err, true))?;
+    }
+    request_next(&mut feed, &mut scheduler, state, channel, app_tx).await
+}
+
+/// The `Have` announcing the blocks appended locally since the length
+/// `announced`, if any.
+fn appended_have(announced: u64, length: u64) -> Option<Have> {
+    if length <= announced {
+        return None;
+    }
+    Some(Have {
+        start: announced,
+        length: Some(length - announced),
+        bitfield: None,
+        ack: None,
+    })
+}
+
 async fn on_have(
-    feed: &mut Arc<Mutex<Feed<RandomAccessProxy>>>,
+    feed: &FeedWrapper<FeedStorage>,
     state: &mut FeedState,
     channel: &mut Channel,
     msg: Have,
-) -> io::Result<()> {
-    feed.lock().await;
-    // Check if the remote announces a new head.
-    log::info!("receive have: {} (state {})", msg.start, state.remote_head);
-    if state.remote_head == 0 || msg.start > state.remote_head {
-        // Store the new remote head.
-        state.remote_head = msg.start;
-        // If we didn't start reading, request first data block.
-        if !state.started {
-            state.started = true;
-            let msg = Request {
-                index: 0,
-                bytes: None,
-                hash: None,
-                nodes: None,
+    app_tx: &mut Sender<AppEvent>,
+) -> anyhow::Result<()> {
+    // Always lock the feed before the scheduler.
+    let end = match message_end(msg.start, msg.length, msg.start.saturating_add(1)) {
+        Some(end) => end,
+        None => return Ok(()),
+    };
+    let (mut feed, mut scheduler) = (feed.feed.lock().await, feed.scheduler.lock().await);
+    log::info!(
+        "receive have: {}..{} (remote_head {:?})",
+        msg.start,
+        end,
+        scheduler.remote_head(state.peer)
+    );
+    // Request more if the remote announces new blocks. The first `Have` may
+    // announce nothing, which completes the initial sync.
+    let new_blocks = scheduler.on_have(state.peer, msg.start..end).is_some();
+    let first = !std::mem::replace(&mut state.remote_answered, true);
+    if new_blocks || first {
+        request_next(&mut feed, &mut scheduler, state, channel, app_tx).await?;
+    }
+    Ok(())
+}
+
+/// Send an extension message, if both sides announced the extension.
+async fn send_extension(
+    state: &FeedState,
+    channel: &mut Channel,
+    name: &str,
+    payload: Vec<u8>,
+) -> anyhow::Result<()> {
+    if !state.remote_extensions.iter().any(|n| n == name) {
+        debug!("remote doesn't support extension {}", name);
+        return Ok(());
+    }
+    let id = match state.local_extensions.iter().position(|n| n == name) {
+        Some(id) => id as u64,
+        None => {
+            debug!("extension {} wasn't announced on this channel", name);
+            return Ok(());
+        }
+    };
+    let msg = ExtensionMessage {
+        id,
+        message: payload,
+    };
+    channel
+        .send(Message::Extension(msg))
+        .await
+        .map_err(|err| ReplicationError::from_io(&err, true))?;
+    Ok(())
+}
+
+async fn on_unhave(
+    feed: &FeedWrapper<FeedStorage>,
+    state: &mut FeedState,
+    channel: &mut Channel,
+    msg: Unhave,
+    app_tx: &mut Sender<AppEvent>,
+) -> anyhow::Result<()> {
+    let end = match message_end(msg.start, msg.length, msg.start.saturating_add(1)) {
+        Some(end) => end,
+        None => return Ok(()),
+    };
+    let (mut feed, mut scheduler) = (feed.feed.lock().await, feed.scheduler.lock().await);
+    debug!("receive unhave: {}..{}", msg.start, end);
+    // Blocks in flight in the range won't arrive, ask for other ones.
+    scheduler.on_unhave(state.peer, msg.start..end);
+    request_next(&mut feed, &mut scheduler, state, channel, app_tx).await
+}
+
+async fn on_status(
+    feed: &FeedWrapper<FeedStorage>,
+    state: &mut FeedState,
+    channel: &mut Channel,
+    msg: Status,
+    app_tx: &mut Sender<AppEvent>,
+) -> anyhow::Result<()> {
+    debug!(
+        "receive status: uploading {:?}, downloading {:?}",
+        msg.uploading, msg.downloading
+    );
+    if msg.downloading == Some(false) {
+        state.uploads.clear();
+    }
+    if let Some(uploading) = msg.uploading {
+        let (mut feed, mut scheduler) = (feed.feed.lock().await, feed.scheduler.lock().await);
+        scheduler.set_uploading(state.peer, uploading);
+        if uploading {
+            request_next(&mut feed, &mut scheduler, state, channel, app_tx).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Announce the blocks we have in the wanted range.
+async fn on_want(
+    feed: &FeedWrapper<FeedStorage>,
+    channel: &mut Channel,
+    msg: Want,
+) -> anyhow::Result<()> {
+    let mut feed = feed.feed.lock().await;
+    let end = match message_end(msg.start, msg.length, feed.len()) {
+        Some(end) => end.min(feed.len()),
+        None => return Ok(()),
+    };
+    let mut start = msg.start;
+    let mut sent = false;
+    while start < end {
+        while start < end && !feed.has(start) {
+            start += 1;
+        }
+        let mut have_end = start;
+        while have_end < end && feed.has(have_end) {
+            have_end += 1;
+        }
+        if have_end > start {
+            let have = Have {
+                start,
+                length: Some(have_end - start),
+                bitfield: None,
+                ack: None,
             };
-            channel.request(msg).await?;
+            channel
+                .send(Message::Have(have))
+                .await
+                .map_err(|err| ReplicationError::from_io(&err, true))?;
+            sent = true;
         }
+        start = have_end;
+    }
+    if !sent {
+        // Tell the remote that we have none of the blocks, so that it
+        // doesn't wait for a `Have` that never comes.
+        let have = Have {
+            start: msg.start,
+            length: Some(0),
+            bitfield: None,
+            ack: None,
+        };
+        channel
+            .send(Message::Have(have))
+            .await
+            .map_err(|err| ReplicationError::from_io(&err, true))?;
     }
     Ok(())
 }
 
+/// Answer the oldest queued request of the remote.
+async fn on_upload(
+    feed: &FeedWrapper<FeedStorage>,
+    state: &mut FeedState,
+    channel: &mut Channel,
+) -> anyhow::Result<()> {
+    let request = match state.uploads.pop_front() {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    // Only the feed is locked while reading, and nothing while sending, so
+    // that a slow peer doesn't hold up the others.
+    let mut guard = feed.feed.lock().await;
+    if !guard.has(request.index) {
+        return Ok(());
+    }
+    let include_hash = request.hash.unwrap_or(false);
+    let value = if include_hash {
+        None
+    } else {
+        guard.get(request.index).await?
+    };
+    let proof = guard
+        .proof_with_digest(request.index, request.nodes.unwrap_or(0), include_hash)
+        .await?;
+    let nodes = proof
+        .nodes
+        .iter()
+        .map(|node| hypercore_protocol::schema::Node {
+            index: node.index(),
+            hash: node.hash().to_vec(),
+            size: node.len(),
+        })
+        .collect();
+    let bytes = value.as_ref().map_or(0, |value| value.len() as u64);
+    let msg = Data {
+        index: request.index,
+        value,
+        nodes,
+        signature: proof
+            .signature
+            .map(|signature| signature.to_bytes().to_vec()),
+    };
+    drop(guard);
+    channel
+        .send(Message::Data(msg))
+        .await
+        .map_err(|err| ReplicationError::from_io(&err, true))?;
+    feed.scheduler.lock().await.on_upload(state.peer, bytes);
+    Ok(())
+}
+
 async fn on_data(
-    feed: &mut Arc<Mutex<Feed<RandomAccessProxy>>>,
+    feed: &FeedWrapper<FeedStorage>,
     state: &mut FeedState,
     channel: &mut Channel,
     msg: Data,
     app_tx: &mut Sender<AppEvent>,
-) -> io::Result<()> {
-    let mut feed = feed.lock().await;
+) -> anyhow::Result<()> {
+    let (mut feed, mut scheduler) = (feed.feed.lock().await, feed.scheduler.lock().await);
     log::info!(
-        "receive data: idx {}, {} bytes (remote_head {})",
+        "receive data: idx {}, {} bytes (remote_head {:?})",
         msg.index,
         msg.value.as_ref().map_or(0, |v| v.len()),
-        state.remote_head
+        scheduler.remote_head(state.peer)
     );
 
     let value: Option<&[u8]> = match msg.value.as_ref() {
@@ -245,7 +1364,10 @@ async fn on_data(
     };
 
     let signature = match msg.signature {
-        Some(bytes) => Some(Signature::try_from(&bytes[..]).unwrap()),
+        Some(bytes) => match Signature::try_from(&bytes[..]) {
+            Ok(signature) => Some(signature),
+            Err(err) => return Err(InvalidData::new(msg.index, err).into()),
+        },
         None => None,
     };
     let nodes = msg
@@ -259,10 +1381,58 @@ async fn on_data(
         signature,
     };
 
-    feed.put(msg.index, value, proof.clone()).await.unwrap();
+    let failures = storage_failures();
+    if let Err(err) = feed.put(msg.index, value, proof.clone()).await {
+        // Hypercore may turn storage errors into plain messages, so failed
+        // storage operations are counted. Only if none failed, the block or
+        // its proof didn't verify.
+        let local =
+            storage_failures() != failures || err.chain().any(|cause| cause.is::<StorageError>());
+        if local {
+            return Err(err);
+        }
+        return Err(InvalidData::new(msg.index, err).into());
+    }
+    let bytes = value.map_or(0, |value| value.len() as u64);
+    let requested = scheduler.on_data(state.peer, msg.index, bytes, js_sys::Date::now());
+
+    if state.synced {
+        if let Some(value) = value {
+            let event = AppEvent::Appended {
+                index: msg.index,
+                data: value.to_vec(),
+            };
+            app_tx.send(event).await?;
+        }
+    }
+
+    if !requested {
+        // Unsolicited data, the outstanding requests are still pending.
+        scheduler.update(&mut |index: u64| feed.has(index));
+        return Ok(());
+    }
+    request_next(&mut feed, &mut scheduler, state, channel, app_tx).await
+}
 
-    let next = msg.index + 1;
-    if state.remote_head >= next {
+/// Request the next blocks from the peer that the scheduler hands out, up to
+/// `MAX_IN_FLIGHT` at a time. Once everything the peer has is local,
+/// `Synced` is emitted the first time.
+///
+/// In sparse mode only selected blocks are requested, and `Synced` means
+/// that all selected blocks the remote has are local.
+async fn request_next(
+    feed: &mut Feed<FeedStorage>,
+    scheduler: &mut Scheduler,
+    state: &mut FeedState,
+    channel: &mut Channel,
+    app_tx: &mut Sender<AppEvent>,
+) -> anyhow::Result<()> {
+    let mut has = |index: u64| feed.has(index);
+    scheduler.update(&mut has);
+    // After the initial sync, only live replication follows new blocks.
+    // Selected blocks, e.g. ones waited for in `get`, are still fetched.
+    let sparse = state.sparse || (state.synced && !state.live);
+    while let Some(next) = scheduler.next_for(state.peer, sparse, js_sys::Date::now(), &mut has) {
         // Request next data block.
         let msg = Request {
             index: next,
@@ -270,34 +1440,85 @@ async fn on_data(
             hash: None,
             nodes: None,
         };
-        channel.request(msg).await?;
-    } else {
-        let mut full_text = String::new();
-        for i in 0..feed.len() {
-            let line = String::from_utf8(feed.get(i).await.unwrap().unwrap()).unwrap();
-            full_text.push_str(&line);
-        }
-        app_tx
-            .send(AppEvent::ContentLoaded(full_text))
+        channel
+            .request(msg)
             .await
-            .unwrap();
+            .map_err(|err| ReplicationError::from_io(&err, true))?;
+    }
+    let synced = state.remote_answered && scheduler.is_synced(state.peer, state.sparse);
+    if !state.synced && synced {
+        state.synced = true;
+        let event = AppEvent::Synced {
+            discovery_key: discovery_key(&feed.public_key().to_bytes()),
+            length: feed.len(),
+        };
+        app_tx.send(event).await?;
     }
-
     Ok(())
 }
 
-/// A FeedState stores the head seq of the remote.
-/// This would have a bitfield to support sparse sync in the actual impl.
+/// A peer sent a block that failed to verify against its proof.
 #[derive(Debug)]
-struct FeedState {
-    pub remote_head: u64,
-    pub started: bool,
+pub struct InvalidData {
+    pub index: u64,
+    pub reason: String,
 }
-impl Default for FeedState {
-    fn default() -> Self {
-        FeedState {
-            remote_head: 0,
-            started: false,
+
+impl InvalidData {
+    fn new(index: u64, reason: impl std::fmt::Display) -> Self {
+        Self {
+            index,
+            reason: reason.to_string(),
         }
     }
 }
+
+impl std::fmt::Display for InvalidData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid data for block {}: {}", self.index, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidData {}
+
+/// A FeedState stores the download progress on one channel. What the
+/// remote has is tracked by the feed's `Scheduler`.
+#[derive(Debug, Default)]
+struct FeedState {
+    /// The peer of this channel in the scheduler.
+    pub peer: PeerId,
+    /// The length up to which locally appended blocks were announced.
+    pub announced: u64,
+    /// Whether everything up to the remote head was downloaded once.
+    pub synced: bool,
+    /// Whether the remote sent a `Have`, so that its head is known. An empty
+    /// remote answers our `Want` with an empty one.
+    pub remote_answered: bool,
+    /// Keep following the remote head after the initial sync.
+    pub live: bool,
+    /// Only request selected blocks.
+    pub sparse: bool,
+    /// Requests of the remote that are not answered yet.
+    pub uploads: VecDeque<Request>,
+    /// Whether the remote closed the channel.
+    pub closed: bool,
+    /// The sorted extension names announced to the remote.
+    pub local_extensions: Vec<String>,
+    /// The sorted extension names the remote announced.
+    pub remote_extensions: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn announce_appended_blocks() {
+        assert!(appended_have(3, 3).is_none());
+        let have = appended_have(3, 5).unwrap();
+        assert_eq!((have.start, have.length), (3, Some(2)));
+        // A header written to an empty feed is announced as block 0.
+        let have = appended_have(0, 1).unwrap();
+        assert_eq!((have.start, have.length), (0, Some(1)));
+    }
+}
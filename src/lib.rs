@@ -1,51 +1,252 @@
 use futures::channel::mpsc;
 use futures::stream::StreamExt;
-use hypercore_protocol::ProtocolBuilder;
 use log::*;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::spawn_local;
 use web_sys::{Document, HtmlElement, Window};
 
+mod archive;
+mod auth;
+mod autobase;
+mod bee;
+mod cache;
+mod download;
+mod drive;
+mod encryption;
+mod extension;
+mod handle;
+mod header;
 mod hypercore;
 mod persistence;
+mod proto;
+mod resolver;
+mod schema;
+mod tree;
 mod utils;
 mod ws;
 
+pub use handle::{AutobaseHandle, BeeHandle, Download, DriveHandle, FeedHandle};
+
+#[cfg(all(test, target_arch = "wasm32"))]
+wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
+
 pub enum AppEvent {
-    ContentLoaded(String),
+    /// Everything up to the remote head of the feed with this discovery key
+    /// was downloaded, the feed has `length` blocks. An empty remote counts
+    /// as synced.
+    Synced {
+        discovery_key: [u8; 32],
+        length: u64,
+    },
+    /// A block was downloaded after the initial sync in live mode.
+    Appended {
+        index: u64,
+        data: Vec<u8>,
+    },
+    /// A peer sent a block that failed verification. The block was dropped
+    /// and the peer disconnected.
+    InvalidData {
+        index: u64,
+        reason: String,
+    },
+    /// A feed the remote announced was resolved and opened.
+    Opened(hypercore::FeedWrapper<persistence::FeedStorage>),
+    Error(anyhow::Error),
 }
 
+/// Replicate the feed with the hex encoded `key` with the peer at `addr`
+/// and show its text once synced. If `allowlist` is given, only peers whose
+/// handshake public key is one of its hex encoded keys are replicated with.
 #[wasm_bindgen]
-pub async fn run_async(addr: String, key: String) -> Result<(), JsValue> {
-    utils::set_panic_hook();
-    console_log::init_with_level(log::Level::Debug).unwrap();
+pub async fn run_async(
+    addr: String,
+    key: String,
+    allowlist: Option<Box<[JsValue]>>,
+) -> Result<(), JsValue> {
+    utils::init();
 
     info!("start: websocket address {}, hypercore key {}", &addr, &key);
 
-    let websocket = ws::WebsocketStream::connect(addr).await.unwrap();
-    let (reader, writer) = websocket.split();
-    let proto = ProtocolBuilder::new(true).connect_rw(reader, writer);
+    let feed = hypercore::open_feed(&key)
+        .await
+        .map_err(|err| utils::to_js_error(&err))?;
+    let mut feedstore = hypercore::FeedStore::new();
+    feedstore.add(feed.clone());
 
-    let (app_tx, mut app_rx) = mpsc::unbounded();
+    let proto = hypercore::connect(&addr)
+        .await
+        .map_err(|err| utils::to_js_error(&err))?;
 
-    spawn_local(async move {
-        info!("call hypercore::replicate");
-        hypercore::replicate(proto, key, app_tx).await.unwrap();
-    });
+    let (app_tx, mut app_rx) = mpsc::unbounded();
+    let options = hypercore::ReplicateOptions::default();
+    let auth = match allowlist {
+        Some(keys) => auth::PeerAuth::allowlist(&keys)?,
+        None => auth::PeerAuth::Any,
+    };
+    let hooks = hypercore::ReplicateHooks {
+        auth,
+        ..hypercore::ReplicateHooks::default()
+    };
+    hypercore::spawn_replicate(proto, feedstore, options, hooks, app_tx);
 
     let (_window, document, body) = get_elements().unwrap();
     let event = app_rx.next().await.unwrap();
     match event {
-        AppEvent::ContentLoaded(content) => {
+        AppEvent::Synced { .. } => {
+            let content = feed
+                .read_text()
+                .await
+                .map_err(|err| utils::to_js_error(&err))?;
             // Manufacture the element we're gonna append
             let val = document.create_element("pre")?;
             val.set_text_content(Some(&content));
             body.append_child(&val)?;
         }
+        AppEvent::Appended { .. } | AppEvent::Opened(_) => {}
+        AppEvent::InvalidData { index, reason } => {
+            let message = format!("Invalid data for block {}: {}", index, reason);
+            return Err(js_sys::Error::new(&message).into());
+        }
+        AppEvent::Error(err) => return Err(utils::to_js_error(&err)),
     }
     Ok(())
 }
 
+/// Open the browser-side feed for a hex encoded public key without
+/// replicating it.
+#[wasm_bindgen]
+pub async fn open_feed(key: String) -> Result<FeedHandle, JsValue> {
+    utils::init();
+    let feed = hypercore::open_feed(&key)
+        .await
+        .map_err(|err| utils::to_js_error(&err))?;
+    Ok(FeedHandle::new(feed))
+}
+
+/// Open the browser-side feed for a hex encoded public key, and check that
+/// its header declares `content_type` if the header is stored locally.
+#[wasm_bindgen]
+pub async fn open_typed_feed(key: String, content_type: String) -> Result<FeedHandle, JsValue> {
+    utils::init();
+    let open = async {
+        let feed = hypercore::open_feed(&key).await?;
+        feed.validate_header(&content_type).await?;
+        Ok(feed)
+    };
+    let feed = open
+        .await
+        .map_err(|err: anyhow::Error| utils::to_js_error(&err))?;
+    Ok(FeedHandle::new(feed))
+}
+
+/// Open the hyperdrive whose metadata feed has the hex encoded public key
+/// `key` without replicating it.
+#[wasm_bindgen]
+pub async fn open_drive(key: String) -> Result<DriveHandle, JsValue> {
+    utils::init();
+    let open = async {
+        let metadata = hypercore::open_feed(&key).await?;
+        let drive = drive::Drive::open(metadata.clone(), false).await?;
+        Ok((metadata, drive))
+    };
+    let (metadata, drive) = open
+        .await
+        .map_err(|err: anyhow::Error| utils::to_js_error(&err))?;
+    Ok(DriveHandle::new(metadata, drive))
+}
+
+/// Open the key-value index stored in the feed with the hex encoded public
+/// key `key` without replicating it.
+#[wasm_bindgen]
+pub async fn open_bee(key: String) -> Result<BeeHandle, JsValue> {
+    utils::init();
+    let open = async {
+        let feed = hypercore::open_feed(&key).await?;
+        bee::Bee::open(feed).await
+    };
+    let bee = open
+        .await
+        .map_err(|err: anyhow::Error| utils::to_js_error(&err))?;
+    Ok(BeeHandle::new(bee))
+}
+
+/// Open a merged log with the local writer feed stored under `name` and the
+/// writers with the hex encoded public keys `writers`. The local feed is
+/// created on first use.
+#[wasm_bindgen]
+pub async fn open_autobase(
+    name: String,
+    writers: Box<[JsValue]>,
+) -> Result<AutobaseHandle, JsValue> {
+    utils::init();
+    let keys = handle::parse_keys(&writers)?;
+    let open = async {
+        let local = hypercore::open_writable_feed(&format!("autobase-{}", name)).await?;
+        let mut remotes = vec![];
+        for key in keys {
+            if key != local.key() {
+                remotes.push(hypercore::open_feed_by_key(key).await?);
+            }
+        }
+        Ok(autobase::Autobase::new(local, remotes))
+    };
+    let autobase = open
+        .await
+        .map_err(|err: anyhow::Error| utils::to_js_error(&err))?;
+    Ok(AutobaseHandle::new(autobase))
+}
+
+/// Import a feed archive created by `FeedHandle.export` and open the feed.
+/// This replaces the stored feed, so it fails if the feed is open.
+#[wasm_bindgen]
+pub async fn import_feed(archive: Vec<u8>) -> Result<FeedHandle, JsValue> {
+    utils::init();
+    let open = async {
+        let archive = archive::Archive::from_bytes(&archive)?;
+        hypercore::import_feed(&archive).await
+    };
+    let feed = open.await.map_err(|err| utils::to_js_error(&err))?;
+    Ok(FeedHandle::new(feed))
+}
+
+/// Encrypt the stored feed with a key derived from `passphrase`.
+///
+/// Must be called before the feed is opened, and before anything is stored
+/// as existing plaintext data is not converted. Opening a feed rejects with
+/// an `EncryptionError` if the key doesn't match the stored one, or if the
+/// storage was created with or without encryption and this call differs.
+#[wasm_bindgen]
+pub async fn encrypt_storage_with_passphrase(passphrase: String) -> Result<(), JsValue> {
+    utils::init();
+    Ok(encryption::use_passphrase(&passphrase).await?)
+}
+
+/// Encrypt the stored feed with a WebCrypto AES-GCM `CryptoKey`. The key
+/// can be non-extractable, it is only used through `crypto.subtle`.
+///
+/// The same restrictions as for `encrypt_storage_with_passphrase` apply.
+#[wasm_bindgen]
+pub fn encrypt_storage_with_key(key: JsValue) {
+    utils::init();
+    encryption::use_crypto_key(&key);
+}
+
+/// Resolves to `{ usage, quota }` of the origin's storage in bytes.
+#[wasm_bindgen]
+pub async fn estimate_storage() -> Result<JsValue, JsValue> {
+    let estimate = persistence::estimate().await?;
+    let result = js_sys::Object::new();
+    js_sys::Reflect::set(&result, &"usage".into(), &estimate.usage.into())?;
+    js_sys::Reflect::set(&result, &"quota".into(), &estimate.quota.into())?;
+    Ok(result.into())
+}
+
+/// Ask the browser to keep this origin's storage under storage pressure.
+/// Resolves to whether the storage is persistent.
+#[wasm_bindgen]
+pub async fn persist_storage() -> Result<bool, JsValue> {
+    Ok(persistence::request_persistence().await?)
+}
+
 fn get_elements() -> Option<(Window, Document, HtmlElement)> {
     let window = web_sys::window().expect("no global `window` exists");
     let document = window.document().expect("should have a document on window");
@@ -1,13 +1,23 @@
-use anyhow::{anyhow, Error};
 use futures::future::FutureExt;
+use futures::lock::Mutex;
 use hypercore::{Storage, Store};
+use hypercore_protocol::discovery_key;
 use log::*;
 use random_access_storage::RandomAccess;
-use std::fmt::Debug;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use anyhow::Result;
-use js_sys::Uint8Array;
+use js_sys::{Array, Reflect, Uint8Array};
 use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::spawn_local;
+
+use crate::cache::{ReadCache, WriteCache};
+use crate::encryption::{storage_cipher, Encrypted};
+use crate::schema;
 
 #[wasm_bindgen(module = "/callbacks.js")]
 extern "C" {
@@ -19,11 +29,19 @@ extern "C" {
     #[wasm_bindgen(catch)]
     pub async fn storage_write(id: &str, offset: u64, data: &[u8]) -> Result<(), JsValue>;
 
+    /// Write several chunks, joining adjacent ones. `offsets[i]` is the
+    /// offset of the `Uint8Array` in `chunks[i]`. Not atomic: a failure
+    /// leaves the chunks before it written.
+    #[wasm_bindgen(catch)]
+    async fn storage_write_batch(id: &str, offsets: Array, chunks: Array) -> Result<(), JsValue>;
+
     /// Read a sequence of bytes at an offset from the backend.
     #[wasm_bindgen(catch)]
     async fn storage_read(id: &str, offset: u64, length: u64) -> Result<JsValue, JsValue>;
 
-    /// Delete a sequence of bytes at an offset from the backend.
+    /// Delete a sequence of bytes at an offset from the backend. Without a
+    /// native delete the bytes are overwritten with zeros, which keeps their
+    /// space in use.
     #[wasm_bindgen(catch)]
     async fn storage_del(id: &str, offset: u64, length: u64) -> Result<(), JsValue>;
 
@@ -45,59 +63,439 @@ extern "C" {
     /// Flush buffered data on the underlying storage resource.
     #[wasm_bindgen(catch)]
     async fn storage_sync_all(id: &str) -> Result<(), JsValue>;
+
+    /// Query `navigator.storage.estimate()`.
+    #[wasm_bindgen(catch)]
+    async fn storage_estimate() -> Result<JsValue, JsValue>;
+
+    /// Query `navigator.storage.persist()`.
+    #[wasm_bindgen(catch)]
+    async fn storage_persist() -> Result<JsValue, JsValue>;
+
+    /// Resolve after the given number of milliseconds.
+    pub async fn sleep(ms: u32);
+}
+
+/// Errors reported by the JavaScript storage backend.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    /// The backend has no record for the requested store.
+    NotFound { id: String },
+    /// The browser refused the write because the origin quota is exhausted.
+    QuotaExceeded { id: String },
+    /// The requested range lies outside of the stored bytes.
+    OutOfBounds {
+        id: String,
+        offset: u64,
+        length: u64,
+        size: u64,
+    },
+    /// Any other failure, with the name and message of the JS error.
+    Backend {
+        id: String,
+        name: String,
+        message: String,
+    },
+    /// The stored data was written in a newer format than this build reads.
+    UnsupportedVersion { found: u32, supported: u32 },
+    /// Encrypting or decrypting a page failed.
+    Encryption { message: String },
+}
+
+impl StorageError {
+    /// Map a rejection from `callbacks.js` to a storage error.
+    ///
+    /// The JS side may throw `Error` or `DOMException` objects as well as
+    /// plain strings, so the name and message are read reflectively.
+    pub fn from_js(id: &str, err: &JsValue) -> Self {
+        count_failure();
+        let id = id.to_string();
+        if let Some(message) = err.as_string() {
+            return StorageError::Backend {
+                id,
+                name: "Error".to_string(),
+                message,
+            };
+        }
+        let name = js_string_property(err, "name").unwrap_or_else(|| "Error".to_string());
+        let message = js_string_property(err, "message").unwrap_or_else(|| format!("{:?}", err));
+        match name.as_str() {
+            "NotFoundError" => StorageError::NotFound { id },
+            "QuotaExceededError" => StorageError::QuotaExceeded { id },
+            _ => StorageError::Backend { id, name, message },
+        }
+    }
+
+    /// The name of the error as exposed to JavaScript.
+    pub fn name(&self) -> &str {
+        match self {
+            StorageError::NotFound { .. } => "NotFoundError",
+            StorageError::QuotaExceeded { .. } => "QuotaExceededError",
+            StorageError::OutOfBounds { .. } => "RangeError",
+            StorageError::Backend { name, .. } => name,
+            StorageError::UnsupportedVersion { .. } => "VersionError",
+            StorageError::Encryption { .. } => "EncryptionError",
+        }
+    }
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StorageError::NotFound { id } => write!(f, "Storage {} not found", id),
+            StorageError::QuotaExceeded { id } => {
+                write!(f, "Storage quota exceeded while writing to {}", id)
+            }
+            StorageError::OutOfBounds {
+                id,
+                offset,
+                length,
+                size,
+            } => write!(
+                f,
+                "Read bounds exceeded in {}. {} < {}..{}",
+                id,
+                size,
+                offset,
+                offset + length
+            ),
+            StorageError::Backend { id, name, message } => {
+                write!(f, "Storage {} failed: {}: {}", id, name, message)
+            }
+            StorageError::UnsupportedVersion { found, supported } => write!(
+                f,
+                "Storage format version {} is newer than the supported version {}",
+                found, supported
+            ),
+            StorageError::Encryption { message } => write!(f, "Encryption failed: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<StorageError> for JsValue {
+    fn from(err: StorageError) -> Self {
+        let js_err = js_sys::Error::new(&err.to_string());
+        js_err.set_name(err.name());
+        js_err.into()
+    }
+}
+
+thread_local! {
+    static FAILURES: Cell<u64> = Cell::new(0);
+}
+
+/// Count a failed storage operation, see `storage_failures`.
+pub(crate) fn count_failure() {
+    FAILURES.with(|failures| failures.set(failures.get() + 1));
+}
+
+/// The number of storage operations that failed so far.
+///
+/// Errors passed through hypercore may lose their type, so a caller that
+/// needs to tell local failures apart compares the count before and after.
+pub fn storage_failures() -> u64 {
+    FAILURES.with(|failures| failures.get())
+}
+
+fn js_string_property(value: &JsValue, key: &str) -> Option<String> {
+    Reflect::get(value, &JsValue::from_str(key))
+        .ok()
+        .and_then(|v| v.as_string())
+}
+
+/// Options for the `RandomAccessProxy` instances of a storage.
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    /// Buffer writes in WASM and flush them to JS in batches.
+    pub write_back: bool,
+    /// Flush as soon as this many dirty bytes are buffered.
+    pub max_dirty_bytes: usize,
+    /// Flush buffered writes this long after the first unflushed write.
+    pub flush_interval_ms: u32,
+    /// Number of pages kept in the read cache of the tree and bitfield
+    /// stores. Zero disables the read cache.
+    pub read_cache_pages: usize,
+    /// Size of a read cache page in bytes.
+    pub read_cache_page_size: u64,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        Self {
+            write_back: true,
+            max_dirty_bytes: 256 * 1024,
+            flush_interval_ms: 1000,
+            read_cache_pages: 256,
+            read_cache_page_size: 4096,
+        }
+    }
+}
+
+/// State shared by all proxies opened on the same store id, so that a
+/// second proxy on a store sees the writes buffered by the feed's proxy,
+/// and its writes invalidate the pages the feed's proxy cached.
+#[derive(Debug, Default)]
+struct SharedStore {
+    write_cache: Mutex<WriteCache>,
+    /// Created by the first proxy that enables the read cache.
+    read_cache: Mutex<Option<ReadCache>>,
+    length: AtomicU64,
+}
+
+thread_local! {
+    static SHARED_STORES: RefCell<HashMap<String, Arc<SharedStore>>> = RefCell::new(HashMap::new());
+}
+
+/// Get the shared state of a store, loading its length from the backend the
+/// first time the store is opened.
+async fn shared_store(id: &str) -> Result<Arc<SharedStore>, StorageError> {
+    if let Some(shared) = SHARED_STORES.with(|stores| stores.borrow().get(id).cloned()) {
+        return Ok(shared);
+    }
+    let length = backend_len(id).await?;
+    let shared = SHARED_STORES.with(|stores| {
+        stores
+            .borrow_mut()
+            .entry(id.to_string())
+            .or_insert_with(|| {
+                Arc::new(SharedStore {
+                    length: AtomicU64::new(length),
+                    ..Default::default()
+                })
+            })
+            .clone()
+    });
+    Ok(shared)
 }
 
 /// Main constructor.
 #[derive(Debug)]
 pub struct RandomAccessProxy {
     id: String,
-    length: u64,
+    options: ProxyOptions,
+    shared: Arc<SharedStore>,
 }
 
 impl RandomAccessProxy {
-    pub fn new(id: String) -> Self {
-        Self { id, length: 0 }
+    /// Open a proxy for the store `id`.
+    ///
+    /// Proxies on the same id share their length, write cache and read
+    /// cache. Only proxies that enable the read cache read through it, but
+    /// writes through any proxy invalidate it.
+    pub async fn open(id: &str, options: ProxyOptions) -> Result<Self, StorageError> {
+        let shared = shared_store(id).await?;
+        Ok(Self::with_shared(id.to_string(), options, shared))
+    }
+
+    fn with_shared(id: String, options: ProxyOptions, shared: Arc<SharedStore>) -> Self {
+        if options.read_cache_pages > 0 {
+            // Nothing else holds the lock while a proxy is opened.
+            if let Some(mut read_cache) = shared.read_cache.try_lock() {
+                read_cache.get_or_insert_with(|| {
+                    ReadCache::new(options.read_cache_page_size, options.read_cache_pages)
+                });
+            }
+        }
+        Self {
+            id,
+            options,
+            shared,
+        }
+    }
+
+    /// Drop the cached pages a write through this or another proxy made
+    /// stale.
+    async fn invalidate_read_cache(&self, invalidate: impl FnOnce(&mut ReadCache)) {
+        if let Some(read_cache) = self.shared.read_cache.lock().await.as_mut() {
+            invalidate(read_cache);
+        }
+    }
+
+    /// Write all buffered ranges to the backend.
+    pub async fn flush(&self) -> Result<(), StorageError> {
+        flush_write_cache(&self.id, &self.shared.write_cache).await
+    }
+
+    /// Flush the write cache after `flush_interval_ms` unless a flush is
+    /// already pending.
+    fn schedule_flush(&self, cache: &mut WriteCache) {
+        if !cache.schedule_flush() {
+            return;
+        }
+        let id = self.id.clone();
+        let shared = self.shared.clone();
+        let interval = self.options.flush_interval_ms;
+        spawn_local(async move {
+            sleep(interval).await;
+            if let Err(err) = flush_write_cache(&id, &shared.write_cache).await {
+                error!("timed flush failed: {}", err);
+            }
+        });
+    }
+
+    fn length(&self) -> u64 {
+        self.shared.length.load(Ordering::Relaxed)
+    }
+
+    /// Serve a read from the page cache, loading all missing pages with a
+    /// single backend read. Pages that still have unflushed bytes in `dirty`
+    /// are not cached, as the backend copy of them is stale.
+    async fn read_cached(
+        &mut self,
+        offset: u64,
+        length: u64,
+        dirty: &WriteCache,
+    ) -> Result<Vec<u8>, StorageError> {
+        if self.options.read_cache_pages == 0 {
+            return read_backend(&self.id, offset, length).await;
+        }
+        let mut read_cache = self.shared.read_cache.lock().await;
+        let cache = match read_cache.as_mut() {
+            Some(cache) => cache,
+            None => return read_backend(&self.id, offset, length).await,
+        };
+        if length == 0 {
+            return Ok(vec![]);
+        }
+        let size = self.length();
+        let page_size = cache.page_size();
+        let page_len = |page: u64| page_size.min(size - page * page_size) as usize;
+        let first = offset / page_size;
+        let last = (offset + length - 1) / page_size;
+
+        let mut pages: Vec<Option<Vec<u8>>> = (first..=last)
+            .map(|page| cache.get(page, page_len(page)).map(|b| b.to_vec()))
+            .collect();
+        let missing_first = pages.iter().position(|p| p.is_none());
+        let missing_last = pages.iter().rposition(|p| p.is_none());
+        if let (Some(lo), Some(hi)) = (missing_first, missing_last) {
+            let start = (first + lo as u64) * page_size;
+            let end = ((first + hi as u64 + 1) * page_size).min(size);
+            let loaded = read_backend(&self.id, start, end - start).await?;
+            for (i, page) in pages.iter_mut().enumerate().take(hi + 1).skip(lo) {
+                let index = first + i as u64;
+                let at = (index * page_size - start) as usize;
+                let bytes = loaded[at..at + page_len(index)].to_vec();
+                if !dirty.overlaps(index * page_size, page_size) {
+                    cache.insert(index, bytes.clone());
+                }
+                *page = Some(bytes);
+            }
+        }
+
+        let joined: Vec<u8> = pages.into_iter().flatten().flatten().collect();
+        let at = (offset - first * page_size) as usize;
+        Ok(joined[at..at + length as usize].to_vec())
+    }
+}
+
+/// The length of store `id` as reported by the backend, without the writes
+/// still buffered.
+pub async fn backend_len(id: &str) -> Result<u64, StorageError> {
+    match storage_len(id).await {
+        Ok(value) => Ok(value.as_f64().unwrap_or(0.0) as u64),
+        Err(err) => Err(StorageError::from_js(id, &err)),
     }
 }
 
+/// Delete bytes of store `id` in the backend, bypassing the caches.
+pub async fn delete_backend(id: &str, offset: u64, length: u64) -> Result<(), StorageError> {
+    storage_del(id, offset, length)
+        .await
+        .map_err(|err| StorageError::from_js(id, &err))
+}
+
+pub async fn read_backend(id: &str, offset: u64, length: u64) -> Result<Vec<u8>, StorageError> {
+    match storage_read(id, offset, length).await {
+        Ok(value) => {
+            let value: Uint8Array = value.unchecked_into();
+            Ok(value.to_vec())
+        }
+        Err(err) => Err(StorageError::from_js(id, &err)),
+    }
+}
+
+async fn flush_write_cache(id: &str, write_cache: &Mutex<WriteCache>) -> Result<(), StorageError> {
+    let mut cache = write_cache.lock().await;
+    cache.flush_done();
+    if cache.is_empty() {
+        return Ok(());
+    }
+    let batch = cache.take();
+    let offsets = Array::new();
+    let chunks = Array::new();
+    for (offset, data) in &batch {
+        offsets.push(&JsValue::from_f64(*offset as f64));
+        chunks.push(&Uint8Array::from(&data[..]));
+    }
+    debug!("flushing {} ranges to {}", batch.len(), id);
+    if let Err(err) = storage_write_batch(id, offsets, chunks).await {
+        // Keep the data so that the next flush retries it.
+        for (offset, data) in batch {
+            cache.write(offset, &data);
+        }
+        return Err(StorageError::from_js(id, &err));
+    }
+    Ok(())
+}
+
 #[async_trait::async_trait(?Send)]
 impl RandomAccess for RandomAccessProxy {
     type Error = Box<dyn std::error::Error + Sync + Send>;
 
     async fn write(&mut self, offset: u64, data: &[u8]) -> Result<(), Self::Error> {
-        // panic!("Not implemented yet");
-        let data = data.to_vec();
         info!("writing to offset {}, id {}", &offset, &self.id);
-        match storage_write(&self.id, offset, &data).await {
-            Ok(_) => {
-                // We've changed the length of our file.
-                let new_len = offset + (data.len() as u64);
-                if new_len > self.length {
-                    self.length = new_len;
-                }
-                Ok(())
+        let new_len = offset + (data.len() as u64);
+        // Reads hold the write cache lock while they fill the read cache, so
+        // holding it here keeps them from caching the old bytes again.
+        let shared = self.shared.clone();
+        let mut cache = shared.write_cache.lock().await;
+        self.invalidate_read_cache(|read_cache| read_cache.invalidate(offset, data.len() as u64))
+            .await;
+        if self.options.write_back {
+            cache.write(offset, data);
+            // The data is readable from the cache from now on, even if the
+            // flush below fails and is retried later.
+            self.shared.length.fetch_max(new_len, Ordering::Relaxed);
+            if cache.dirty_bytes() >= self.options.max_dirty_bytes {
+                drop(cache);
+                self.flush().await?;
+            } else {
+                self.schedule_flush(&mut cache);
+            }
+        } else {
+            if let Err(err) = storage_write(&self.id, offset, data).await {
+                return Err(StorageError::from_js(&self.id, &err).into());
             }
-            Err(err) => Err(err.as_string().unwrap().into()),
+            self.shared.length.fetch_max(new_len, Ordering::Relaxed);
         }
+        Ok(())
     }
 
     async fn read(&mut self, offset: u64, length: u64) -> Result<Vec<u8>, Self::Error> {
-        if (offset + length) as u64 > self.length {
-            return Err(anyhow!(
-                "Read bounds exceeded. {} < {}..{}",
-                self.length,
+        if (offset + length) as u64 > self.length() {
+            return Err(StorageError::OutOfBounds {
+                id: self.id.clone(),
                 offset,
-                offset + length
-            )
+                length,
+                size: self.length(),
+            }
             .into());
         }
-        match storage_read(&self.id, offset, length).await {
-            Ok(value) => {
-                let value: Uint8Array = value.unchecked_into();
-                Ok(value.to_vec())
-            }
-            Err(err) => Err(err.as_string().unwrap().into()),
+        let shared = self.shared.clone();
+        let mut dirty = shared.write_cache.lock().await;
+        if let Some(data) = dirty.get(offset, length) {
+            return Ok(data);
         }
+        if dirty.overlaps(offset, length) {
+            // Partially buffered, write it out before reading it back.
+            drop(dirty);
+            self.flush().await?;
+            dirty = shared.write_cache.lock().await;
+        }
+        Ok(self.read_cached(offset, length, &dirty).await?)
     }
 
     async fn read_to_writer(
@@ -106,52 +504,190 @@ impl RandomAccess for RandomAccessProxy {
         _length: u64,
         _buf: &mut (impl async_std::io::Write + Send),
     ) -> Result<(), Self::Error> {
-        unimplemented!();
+        Err(StorageError::Backend {
+            id: self.id.clone(),
+            name: "NotSupportedError".to_string(),
+            message: "The store can't be read to a writer".to_string(),
+        }
+        .into())
     }
 
     async fn del(&mut self, offset: u64, length: u64) -> Result<(), Self::Error> {
+        // Truncating frees the space on every backend.
+        if offset < self.length() && offset + length >= self.length() {
+            return self.truncate(offset).await;
+        }
+        self.flush().await?;
+        let shared = self.shared.clone();
+        let _dirty = shared.write_cache.lock().await;
+        self.invalidate_read_cache(|cache| cache.invalidate(offset, length))
+            .await;
         match storage_del(&self.id, offset, length).await {
             Ok(()) => Ok(()),
-            Err(err) => Err(err.as_string().unwrap().into()),
+            Err(err) => Err(StorageError::from_js(&self.id, &err).into()),
         }
-
-        // panic!("Not implemented yet");
     }
 
     async fn truncate(&mut self, length: u64) -> Result<(), Self::Error> {
-        panic!("Not implemented yet");
+        let shared = self.shared.clone();
+        let mut dirty = shared.write_cache.lock().await;
+        dirty.truncate(length);
+        self.invalidate_read_cache(|cache| cache.invalidate_from(length))
+            .await;
+        if let Err(err) = storage_truncate(&self.id, length).await {
+            return Err(StorageError::from_js(&self.id, &err).into());
+        }
+        self.shared.length.store(length, Ordering::Relaxed);
+        Ok(())
     }
 
     async fn len(&self) -> Result<u64, Self::Error> {
-        Ok(self.length)
+        Ok(self.length())
     }
 
     async fn is_empty(&mut self) -> Result<bool, Self::Error> {
-        Ok(self.length == 0)
+        Ok(self.length() == 0)
     }
 
     async fn sync_all(&mut self) -> Result<(), Self::Error> {
-        Ok(())
+        self.flush().await?;
+        match storage_sync_all(&self.id).await {
+            Ok(()) => Ok(()),
+            Err(err) => Err(StorageError::from_js(&self.id, &err).into()),
+        }
+    }
+}
+
+/// The storage of a feed: proxies, encrypted if encryption is enabled.
+pub type FeedStorage = Encrypted<RandomAccessProxy>;
+
+/// Open a store of the feed besides the feed's own storage, e.g. to read the
+/// tree directly. Reads bypass the read cache, which the store's writes
+/// still invalidate, see `RandomAccessProxy::open`.
+pub async fn open_store(id: &str) -> Result<FeedStorage, StorageError> {
+    let options = ProxyOptions {
+        read_cache_pages: 0,
+        ..Default::default()
+    };
+    let proxy = RandomAccessProxy::open(id, options).await?;
+    Ok(Encrypted::new(proxy, storage_cipher()))
+}
+
+/// The store ids of a feed, in the order hypercore creates them.
+///
+/// These ids are part of the on-disk format, changing them needs a
+/// migration in `schema`.
+pub const STORE_IDS: [&str; 5] = ["tree", "data", "bitfield", "signatures", "key"];
+
+/// The backend id of a hypercore store.
+pub fn store_id(store: &Store) -> &'static str {
+    match store {
+        Store::Tree => "tree",
+        Store::Data => "data",
+        Store::Bitfield => "bitfield",
+        Store::Signatures => "signatures",
+        Store::Keypair => "key",
+    }
+}
+
+/// The backend id of a store of the feed in `namespace`. The empty
+/// namespace keeps the ids of `STORE_IDS`, which held the single feed of
+/// schema versions before 3.
+pub fn namespaced_id(namespace: &str, id: &str) -> String {
+    if namespace.is_empty() {
+        id.to_string()
+    } else {
+        format!("{}/{}", namespace, id)
     }
 }
 
+/// The namespace of the stores of the feed with public key `key`, the hex
+/// encoded discovery key.
+pub fn key_namespace(key: &[u8]) -> String {
+    hex::encode(discovery_key(key))
+}
+
+/// Bytes currently held by the store `id`, including buffered writes. A
+/// store that wasn't opened yet counts as empty.
+pub fn stored_len(id: &str) -> u64 {
+    SHARED_STORES.with(|stores| {
+        stores
+            .borrow()
+            .get(id)
+            .map_or(0, |shared| shared.length.load(Ordering::Relaxed))
+    })
+}
+
+/// Origin-wide storage usage and quota as reported by the browser.
+#[derive(Debug, Clone, Copy)]
+pub struct StorageEstimate {
+    pub usage: f64,
+    pub quota: f64,
+}
+
+/// Query `navigator.storage.estimate()`.
+pub async fn estimate() -> Result<StorageEstimate, StorageError> {
+    let value = storage_estimate()
+        .await
+        .map_err(|err| StorageError::from_js("estimate", &err))?;
+    let number = |key: &str| {
+        Reflect::get(&value, &JsValue::from_str(key))
+            .ok()
+            .and_then(|v| v.as_f64())
+            .unwrap_or(0.0)
+    };
+    Ok(StorageEstimate {
+        usage: number("usage"),
+        quota: number("quota"),
+    })
+}
+
+/// Ask the browser not to evict this origin's storage under pressure.
+/// Returns whether storage is now persistent.
+pub async fn request_persistence() -> Result<bool, StorageError> {
+    let value = storage_persist()
+        .await
+        .map_err(|err| StorageError::from_js("persist", &err))?;
+    Ok(value.as_bool().unwrap_or(false))
+}
+
 pub struct WasmStorage<T>(Storage<T>)
 where
     T: RandomAccess + Debug;
 
-impl WasmStorage<RandomAccessProxy> {
-    /// Create a new instance backed by a `RandomAccessProxy` instance.
-    pub async fn new_proxy() -> Result<Storage<RandomAccessProxy>> {
-        let create = |store: Store| {
+impl WasmStorage<FeedStorage> {
+    /// Create a new proxy-backed instance with the stores of the feed in
+    /// `namespace`.
+    pub async fn new_proxy_in(
+        namespace: &str,
+        options: ProxyOptions,
+    ) -> Result<Storage<FeedStorage>> {
+        schema::migrate().await?;
+        // Opening a shared store talks to JS, which can't happen inside the
+        // `Send` futures hypercore expects from `create`.
+        let mut stores = HashMap::new();
+        for id in STORE_IDS.iter() {
+            let full_id = namespaced_id(namespace, id);
+            let shared = shared_store(&full_id).await?;
+            stores.insert(*id, (full_id, shared));
+        }
+        let cipher = storage_cipher();
+        let create = move |store: Store| {
+            let options = options.clone();
+            let cipher = cipher.clone();
+            let (id, shared) = stores[store_id(&store)].clone();
             async move {
-                let name = match store {
-                    Store::Tree => "tree",
-                    Store::Data => "data",
-                    Store::Bitfield => "bitfield",
-                    Store::Signatures => "signatures",
-                    Store::Keypair => "key",
+                let options = match store {
+                    // Only the tree and bitfield are read often enough
+                    // during verification to be worth caching.
+                    Store::Tree | Store::Bitfield => options,
+                    _ => ProxyOptions {
+                        read_cache_pages: 0,
+                        ..options
+                    },
                 };
-                Ok(RandomAccessProxy::new(name.to_string()))
+                let proxy = RandomAccessProxy::with_shared(id, options, shared);
+                Ok(Encrypted::new(proxy, cipher))
             }
             .boxed()
         };
@@ -0,0 +1,109 @@
+//! Minimal protobuf encoding for the messages the Node.js data structures
+//! store in feed blocks.
+//!
+//! Only the wire types these messages use are written. When reading,
+//! fields of any wire type are accepted so unknown fields can be skipped.
+
+use anyhow::{anyhow, bail, Result};
+
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LENGTH_DELIMITED: u64 = 2;
+const FIXED32: u64 = 5;
+
+/// The value of a decoded field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A fixed size value, which none of our messages use.
+    Fixed,
+}
+
+impl<'a> Value<'a> {
+    pub fn as_u64(&self) -> Result<u64> {
+        match self {
+            Value::Varint(value) => Ok(*value),
+            _ => bail!("Expected a varint field"),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<&'a [u8]> {
+        match self {
+            Value::Bytes(value) => Ok(value),
+            _ => bail!("Expected a length delimited field"),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String> {
+        Ok(String::from_utf8(self.as_bytes()?.to_vec())?)
+    }
+}
+
+/// Write a length delimited field.
+pub fn write_bytes(bytes: &mut Vec<u8>, field: u64, value: &[u8]) {
+    write_varint(bytes, field << 3 | LENGTH_DELIMITED);
+    write_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value);
+}
+
+/// Write a varint field.
+pub fn write_uint(bytes: &mut Vec<u8>, field: u64, value: u64) {
+    write_varint(bytes, field << 3 | VARINT);
+    write_varint(bytes, value);
+}
+
+pub fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+pub fn read_varint(bytes: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = (*bytes)
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated varint"))?;
+        *bytes = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("Varint is too long")
+}
+
+/// Read the next field and its number from `bytes`.
+pub fn read_field<'a>(bytes: &mut &'a [u8]) -> Result<(u64, Value<'a>)> {
+    let key = read_varint(bytes)?;
+    let (field, wire_type) = (key >> 3, key & 0x7);
+    let value = match wire_type {
+        VARINT => Value::Varint(read_varint(bytes)?),
+        FIXED64 => {
+            take(bytes, 8)?;
+            Value::Fixed
+        }
+        LENGTH_DELIMITED => {
+            let len = read_varint(bytes)? as usize;
+            Value::Bytes(take(bytes, len)?)
+        }
+        FIXED32 => {
+            take(bytes, 4)?;
+            Value::Fixed
+        }
+        _ => bail!("Invalid wire type {}", wire_type),
+    };
+    Ok((field, value))
+}
+
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if bytes.len() < len {
+        bail!("Truncated field");
+    }
+    let (value, rest) = (*bytes).split_at(len);
+    *bytes = rest;
+    Ok(value)
+}
@@ -0,0 +1,68 @@
+//! Resolving discovery keys the remote opens to feeds we may replicate.
+
+use hypercore_protocol::discovery_key;
+use js_sys::{Function, Promise};
+use log::*;
+use std::convert::TryInto;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+
+/// Maps a discovery key the remote announces to the public key of a feed
+/// that is not opened yet.
+#[derive(Debug, Clone)]
+pub enum KeyResolver {
+    /// Only feeds that are already open are replicated.
+    None,
+    /// Any of these public keys may be opened.
+    Keys(Vec<[u8; 32]>),
+    /// A JS function called with the hex encoded discovery key. It returns
+    /// the hex encoded public key, or a `Promise` of it, or `null` to refuse.
+    Callback(Function),
+}
+
+impl Default for KeyResolver {
+    fn default() -> Self {
+        KeyResolver::None
+    }
+}
+
+impl KeyResolver {
+    /// The public key for `discovery_key`, if the app wants to replicate it.
+    pub async fn resolve(&self, dkey: &[u8]) -> Option<[u8; 32]> {
+        match self {
+            KeyResolver::None => None,
+            KeyResolver::Keys(keys) => keys
+                .iter()
+                .find(|key| discovery_key(&key[..])[..] == *dkey)
+                .copied(),
+            KeyResolver::Callback(callback) => {
+                let arg = JsValue::from(hex::encode(dkey));
+                let result = match callback.call1(&JsValue::NULL, &arg) {
+                    Ok(result) => result,
+                    Err(err) => {
+                        error!("key resolver failed: {:?}", err);
+                        return None;
+                    }
+                };
+                let result = match result.dyn_into::<Promise>() {
+                    Ok(promise) => JsFuture::from(promise).await,
+                    Err(result) => Ok(result),
+                };
+                let key = match result {
+                    Ok(key) => key.as_string()?,
+                    Err(err) => {
+                        error!("key resolver failed: {:?}", err);
+                        return None;
+                    }
+                };
+                let key: [u8; 32] = hex::decode(&key).ok()?.try_into().ok()?;
+                // Don't trust the callback to return the matching key.
+                if discovery_key(&key)[..] != *dkey {
+                    warn!("key resolver returned a key for another discovery key");
+                    return None;
+                }
+                Some(key)
+            }
+        }
+    }
+}
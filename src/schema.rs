@@ -0,0 +1,367 @@
+//! Format versioning of the data persisted in IndexedDB.
+//!
+//! A small record in the `meta` store holds the version of the on-disk
+//! layout. Before the storage is opened, `migrate` brings older layouts up
+//! to `CURRENT_VERSION` by running the registered migrations in order.
+//!
+//! Since version 2, the record is followed by whether the stores are
+//! encrypted, and if so a check value sealed with the key. Opening with a
+//! cipher that doesn't match fails. Stores from before version 2 are
+//! recorded as unknown until an open can tell from the stored bytes.
+//!
+//! Since version 3, the stores of every feed are prefixed by its discovery
+//! key, see `persistence::key_namespace`.
+
+use anyhow::{anyhow, Result};
+use futures::future::{FutureExt, LocalBoxFuture};
+use hypercore::Store;
+use log::*;
+use random_access_storage::RandomAccess;
+
+use crate::encryption::{storage_cipher, Cipher, CHECK_SIZE};
+use crate::persistence::{
+    backend_len, delete_backend, key_namespace, namespaced_id, open_store, read_backend,
+    storage_write, store_id, ProxyOptions, RandomAccessProxy, StorageError, STORE_IDS,
+};
+
+/// The version of the layout written by this build.
+pub const CURRENT_VERSION: u32 = 3;
+
+const META_ID: &str = "meta";
+const META_MAGIC: &[u8; 4] = b"HCWM";
+const META_SIZE: u64 = 8;
+/// The encryption record follows the version: a flag byte and the check
+/// value, zero for plaintext stores.
+const ENCRYPTION_OFFSET: u64 = META_SIZE;
+const ENCRYPTION_SIZE: u64 = 1 + CHECK_SIZE;
+const PLAINTEXT: u8 = 0;
+const ENCRYPTED: u8 = 1;
+/// Not recorded by the layout the stores were written in.
+const UNKNOWN_ENCRYPTION: u8 = 2;
+/// Plaintext lengths of the keypair store: the public key, optionally
+/// followed by a 32 or 64 byte secret key. Encrypted stores are longer by
+/// the page overhead, so no encrypted length is among them.
+const PLAINTEXT_KEYPAIR_SIZES: [u64; 3] = [32, 64, 96];
+/// Bytes moved at once when copying a store.
+const COPY_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// A step from the previous format version to `version`.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: fn() -> LocalBoxFuture<'static, Result<()>>,
+}
+
+/// All migrations, ordered by version.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "record the format version",
+        run: record_version,
+    },
+    Migration {
+        version: 2,
+        description: "add the encryption record, unknown until checked",
+        run: record_unknown_encryption,
+    },
+    Migration {
+        version: 3,
+        description: "move the stores of the unnamespaced feed under its discovery key",
+        run: namespace_legacy_feed,
+    },
+];
+
+/// Version 0 is the unversioned layout, which version 1 only adds the meta
+/// record to.
+fn record_version() -> LocalBoxFuture<'static, Result<()>> {
+    async { Ok(()) }.boxed_local()
+}
+
+/// Earlier versions didn't record the encryption. The cipher set on the
+/// first open after upgrading may not be the one the stores use, so the
+/// encryption is recorded as unknown until `check_encryption` can tell.
+fn record_unknown_encryption() -> LocalBoxFuture<'static, Result<()>> {
+    async {
+        let mut bytes = vec![0; ENCRYPTION_SIZE as usize];
+        bytes[0] = UNKNOWN_ENCRYPTION;
+        write_encryption_record(&bytes).await
+    }
+    .boxed_local()
+}
+
+/// Up to version 2, `open_feed` kept its feed in the unprefixed stores.
+/// Their raw, possibly encrypted, bytes are moved to the stores named after
+/// the feed's discovery key. Pages are sealed with their index only, so the
+/// copies stay readable.
+fn namespace_legacy_feed() -> LocalBoxFuture<'static, Result<()>> {
+    async {
+        // The public key is read through the cipher, so check it first.
+        check_encryption().await?;
+        let mut keypair = open_store(store_id(&Store::Keypair)).await?;
+        if keypair.len().await.map_err(|err| anyhow!(err))? < 32 {
+            return Ok(());
+        }
+        let key = keypair.read(0, 32).await.map_err(|err| anyhow!(err))?;
+        let namespace = key_namespace(&key);
+        for id in STORE_IDS.iter() {
+            move_store(id, &namespaced_id(&namespace, id)).await?;
+        }
+        Ok(())
+    }
+    .boxed_local()
+}
+
+/// Copy the raw bytes of store `from` to store `to` chunk by chunk, then
+/// empty `from`.
+async fn move_store(from: &str, to: &str) -> Result<()> {
+    let options = ProxyOptions {
+        read_cache_pages: 0,
+        ..Default::default()
+    };
+    let mut source = RandomAccessProxy::open(from, options.clone()).await?;
+    let mut target = RandomAccessProxy::open(to, options).await?;
+    let len = source.len().await.map_err(|err| anyhow!(err))?;
+    target.truncate(0).await.map_err(|err| anyhow!(err))?;
+    let mut offset = 0;
+    while offset < len {
+        let length = COPY_CHUNK_SIZE.min(len - offset);
+        let bytes = source
+            .read(offset, length)
+            .await
+            .map_err(|err| anyhow!(err))?;
+        target
+            .write(offset, &bytes)
+            .await
+            .map_err(|err| anyhow!(err))?;
+        offset += length;
+    }
+    target.sync_all().await.map_err(|err| anyhow!(err))?;
+    source.truncate(0).await.map_err(|err| anyhow!(err))?;
+    source.sync_all().await.map_err(|err| anyhow!(err))?;
+    // Backends without a truncate ignore it, the bytes are zeroed instead so
+    // that no copy of the feed is left behind.
+    if backend_len(from).await? > 0 {
+        warn!("{} could not be truncated, zeroing it", from);
+        let mut offset = 0;
+        while offset < len {
+            let length = COPY_CHUNK_SIZE.min(len - offset);
+            delete_backend(from, offset, length).await?;
+            offset += length;
+        }
+    }
+    Ok(())
+}
+
+async fn write_encryption(cipher: Option<&Cipher>) -> Result<()> {
+    let bytes = match cipher {
+        Some(cipher) => {
+            let mut bytes = vec![ENCRYPTED];
+            bytes.extend_from_slice(&cipher.check_value().await?);
+            bytes
+        }
+        None => vec![PLAINTEXT; ENCRYPTION_SIZE as usize],
+    };
+    write_encryption_record(&bytes).await
+}
+
+async fn write_encryption_record(bytes: &[u8]) -> Result<()> {
+    if let Err(err) = storage_write(META_ID, ENCRYPTION_OFFSET, bytes).await {
+        return Err(StorageError::from_js(META_ID, &err).into());
+    }
+    Ok(())
+}
+
+/// Whether `cipher`, or no cipher, matches the stores written before the
+/// encryption was recorded. Only the unprefixed keypair store can exist
+/// then, as namespacing needs the encryption to be known.
+async fn matches_unrecorded_encryption(cipher: Option<&Cipher>) -> Result<bool> {
+    let options = ProxyOptions {
+        read_cache_pages: 0,
+        ..Default::default()
+    };
+    let mut keypair = RandomAccessProxy::open(store_id(&Store::Keypair), options).await?;
+    let len = keypair.len().await.map_err(|err| anyhow!(err))?;
+    if len == 0 {
+        // No feed was stored, so there is nothing to misread.
+        return Ok(true);
+    }
+    match cipher {
+        None => Ok(PLAINTEXT_KEYPAIR_SIZES.contains(&len)),
+        Some(cipher) => {
+            let bytes = keypair.read(0, len).await.map_err(|err| anyhow!(err))?;
+            Ok(cipher.opens_first_page(&bytes).await)
+        }
+    }
+}
+
+/// Fail unless the configured cipher matches the recorded encryption.
+async fn check_encryption() -> Result<()> {
+    let record = read_backend(META_ID, ENCRYPTION_OFFSET, ENCRYPTION_SIZE)
+        .await
+        .ok()
+        .filter(|record| record.len() as u64 == ENCRYPTION_SIZE)
+        .ok_or_else(|| StorageError::Encryption {
+            message: "The storage has no encryption record".to_string(),
+        })?;
+    if record[0] == UNKNOWN_ENCRYPTION {
+        let cipher = storage_cipher();
+        if matches_unrecorded_encryption(cipher.as_ref()).await? {
+            return write_encryption(cipher.as_ref()).await;
+        }
+        let message = match cipher {
+            Some(_) => "The storage is not encrypted with the key that was set",
+            None => "The storage is encrypted, but no key was set",
+        };
+        return Err(StorageError::Encryption {
+            message: message.to_string(),
+        }
+        .into());
+    }
+    let message = match (record[0] != PLAINTEXT, storage_cipher()) {
+        (false, None) => return Ok(()),
+        (true, Some(cipher)) => {
+            if cipher.verify_check_value(&record[1..]).await {
+                return Ok(());
+            }
+            "The storage is encrypted with a different key"
+        }
+        (true, None) => "The storage is encrypted, but no key was set",
+        (false, Some(_)) => "The storage is not encrypted, but a key was set",
+    };
+    Err(StorageError::Encryption {
+        message: message.to_string(),
+    }
+    .into())
+}
+
+/// Read the stored format version, if there is a meta record.
+pub async fn stored_version() -> Result<Option<u32>> {
+    // A missing record may show up as an error or as empty bytes depending on
+    // the backend, so both count as no record.
+    let bytes = match read_backend(META_ID, 0, META_SIZE).await {
+        Ok(bytes) => bytes,
+        Err(_) => return Ok(None),
+    };
+    if bytes.len() as u64 != META_SIZE || &bytes[..4] != META_MAGIC {
+        return Ok(None);
+    }
+    let mut version = [0u8; 4];
+    version.copy_from_slice(&bytes[4..]);
+    Ok(Some(u32::from_be_bytes(version)))
+}
+
+async fn write_version(version: u32) -> Result<()> {
+    let mut bytes = META_MAGIC.to_vec();
+    bytes.extend_from_slice(&version.to_be_bytes());
+    if let Err(err) = storage_write(META_ID, 0, &bytes).await {
+        return Err(StorageError::from_js(META_ID, &err).into());
+    }
+    Ok(())
+}
+
+/// Whether a feed has been stored before. The tree store always starts with
+/// a header once a feed was opened.
+async fn has_data() -> bool {
+    match read_backend(store_id(&Store::Tree), 0, 32).await {
+        Ok(header) => header.iter().any(|b| *b != 0),
+        Err(_) => false,
+    }
+}
+
+/// The migrations of `migrations` that bring `version` up to date, in
+/// order. Fails for versions newer than the last migration.
+fn pending_migrations(
+    migrations: &[Migration],
+    version: u32,
+) -> Result<Vec<&Migration>, StorageError> {
+    let supported = migrations.iter().map(|m| m.version).max().unwrap_or(0);
+    if version > supported {
+        return Err(StorageError::UnsupportedVersion {
+            found: version,
+            supported,
+        });
+    }
+    let mut pending: Vec<&Migration> = migrations.iter().filter(|m| m.version > version).collect();
+    pending.sort_by_key(|m| m.version);
+    Ok(pending)
+}
+
+/// Bring the stored data up to `CURRENT_VERSION` and check the encryption
+/// key against the stored one. Returns the version.
+pub async fn migrate() -> Result<u32> {
+    let mut version = match stored_version().await? {
+        Some(version) => version,
+        None if !has_data().await => {
+            write_encryption(storage_cipher().as_ref()).await?;
+            write_version(CURRENT_VERSION).await?;
+            return Ok(CURRENT_VERSION);
+        }
+        None => 0,
+    };
+    for migration in pending_migrations(MIGRATIONS, version)? {
+        info!(
+            "migrating storage to version {}: {}",
+            migration.version, migration.description
+        );
+        (migration.run)().await?;
+        version = migration.version;
+        write_version(version).await?;
+    }
+    check_encryption().await?;
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop() -> LocalBoxFuture<'static, Result<()>> {
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn migration(version: u32) -> Migration {
+        Migration {
+            version,
+            description: "test",
+            run: noop,
+        }
+    }
+
+    fn versions(migrations: &[Migration], version: u32) -> Vec<u32> {
+        pending_migrations(migrations, version)
+            .unwrap()
+            .iter()
+            .map(|m| m.version)
+            .collect()
+    }
+
+    #[test]
+    fn migrations_are_ordered() {
+        let registered: Vec<u32> = MIGRATIONS.iter().map(|m| m.version).collect();
+        let expected: Vec<u32> = (1..=CURRENT_VERSION).collect();
+        assert_eq!(registered, expected);
+    }
+
+    #[test]
+    fn run_pending_in_order() {
+        let migrations = [migration(2), migration(1), migration(3)];
+        assert_eq!(versions(&migrations, 0), vec![1, 2, 3]);
+        assert_eq!(versions(&migrations, 1), vec![2, 3]);
+        assert_eq!(versions(&migrations, 3), Vec::<u32>::new());
+        assert_eq!(versions(MIGRATIONS, 0), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn reject_newer_version() {
+        let migrations = [migration(1), migration(2)];
+        assert_eq!(
+            pending_migrations(&migrations, 3).unwrap_err(),
+            StorageError::UnsupportedVersion {
+                found: 3,
+                supported: 2
+            }
+        );
+        let err = pending_migrations(MIGRATIONS, CURRENT_VERSION + 1).unwrap_err();
+        assert_eq!(err.name(), "VersionError");
+    }
+}
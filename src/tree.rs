@@ -0,0 +1,251 @@
+//! Byte offsets and hashes of blocks, read from the Merkle tree store.
+//!
+//! Every tree node stores the byte size of the blocks it spans, so the
+//! offset of a block is the summed size of the full roots to its left.
+
+use anyhow::{anyhow, bail, Result};
+use blake2_rfc::blake2b::Blake2b;
+use random_access_storage::RandomAccess;
+use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
+
+use crate::persistence::{open_store, FeedStorage, StorageError};
+
+/// Size of the tree store header.
+const HEADER_SIZE: u64 = 32;
+/// Size of a stored node: a 32 byte hash followed by a big endian u64 size.
+const NODE_SIZE: u64 = 40;
+/// Type prefix of leaf hashes.
+const LEAF_TYPE: u8 = 0;
+/// Type prefix of the hash of the roots, which the signatures sign.
+const ROOT_TYPE: u8 = 2;
+
+/// The hash stored in the tree for a block with the content `data`.
+pub fn leaf_hash(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Blake2b::new(32);
+    hasher.update(&[LEAF_TYPE]);
+    hasher.update(&(data.len() as u64).to_be_bytes());
+    hasher.update(data);
+    hasher.finalize().as_bytes().to_vec()
+}
+
+/// The hash signed for a tree with the roots `(index, hash, size)`.
+pub fn roots_hash(roots: &[(u64, &[u8], u64)]) -> Vec<u8> {
+    let mut hasher = Blake2b::new(32);
+    hasher.update(&[ROOT_TYPE]);
+    for (index, hash, size) in roots {
+        hasher.update(hash);
+        hasher.update(&index.to_be_bytes());
+        hasher.update(&size.to_be_bytes());
+    }
+    hasher.finalize().as_bytes().to_vec()
+}
+
+/// The hash and size of the node at `index` in the bytes of a tree store,
+/// or `None` if the node is not stored.
+pub fn stored_node(tree: &[u8], index: u64) -> Option<(&[u8], u64)> {
+    let offset = usize::try_from(HEADER_SIZE + NODE_SIZE * index).ok()?;
+    let node = tree.get(offset..offset.checked_add(NODE_SIZE as usize)?)?;
+    let (hash, size) = node.split_at(32);
+    if hash.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    Some((hash, u64::from_be_bytes(size.try_into().ok()?)))
+}
+
+/// Whether the bytes of a tree store hold any node.
+pub fn has_stored_nodes(tree: &[u8]) -> bool {
+    tree.iter()
+        .skip(HEADER_SIZE as usize)
+        .any(|byte| *byte != 0)
+}
+
+/// The flat-tree indices of the full roots spanning all blocks before the
+/// leaf at `index`.
+pub fn full_roots(index: u64) -> Vec<u64> {
+    let mut roots = vec![];
+    let mut remaining = index / 2;
+    let mut offset = 0;
+    while remaining > 0 {
+        let mut factor = 1;
+        while factor * 2 <= remaining {
+            factor *= 2;
+        }
+        roots.push(offset + factor - 1);
+        offset += 2 * factor;
+        remaining -= factor;
+    }
+    roots
+}
+
+/// The depth of a flat-tree node, 0 for leaves.
+pub fn depth(index: u64) -> u32 {
+    index.trailing_ones()
+}
+
+/// The left and right children of a flat-tree node that is not a leaf.
+pub fn children(index: u64) -> (u64, u64) {
+    let half = 1 << (depth(index) - 1);
+    (index - half, index + half)
+}
+
+/// The block index of the leftmost leaf below a flat-tree node.
+pub fn first_block(index: u64) -> u64 {
+    (index - ((1 << depth(index)) - 1)) / 2
+}
+
+/// Read-only access to the sizes stored in a feed's tree.
+pub struct TreeReader {
+    tree: FeedStorage,
+}
+
+impl TreeReader {
+    pub async fn open(id: &str) -> Result<Self> {
+        let tree = open_store(id).await?;
+        Ok(Self { tree })
+    }
+
+    /// The hash of the node at `index`.
+    pub async fn node_hash(&mut self, index: u64) -> Result<Vec<u8>> {
+        let offset = HEADER_SIZE + NODE_SIZE * index;
+        self.tree.read(offset, 32).await.map_err(|err| anyhow!(err))
+    }
+
+    /// The byte size of all blocks spanned by the node at `index`, or `None`
+    /// if the node is not stored. Nodes are only stored once a block below
+    /// them or a proof covering them was received.
+    pub async fn stored_node_size(&mut self, index: u64) -> Result<Option<u64>> {
+        let offset = HEADER_SIZE + NODE_SIZE * index;
+        let node = match self.tree.read(offset, NODE_SIZE).await {
+            Ok(node) => node,
+            // Nodes past the end of the store are missing.
+            Err(err)
+                if matches!(
+                    err.downcast_ref::<StorageError>(),
+                    Some(StorageError::OutOfBounds { .. })
+                ) =>
+            {
+                return Ok(None)
+            }
+            Err(err) => return Err(anyhow!(err)),
+        };
+        if node[..32].iter().all(|byte| *byte == 0) {
+            return Ok(None);
+        }
+        let mut size = [0u8; 8];
+        size.copy_from_slice(&node[32..]);
+        Ok(Some(u64::from_be_bytes(size)))
+    }
+
+    /// The byte offset at which block `index` starts in the data store.
+    pub async fn byte_offset(&mut self, index: u64) -> Result<u64> {
+        let mut offset = 0;
+        for root in full_roots(2 * index) {
+            offset += self
+                .stored_node_size(root)
+                .await?
+                .ok_or_else(|| anyhow!("Tree node {} is not stored", root))?;
+        }
+        Ok(offset)
+    }
+
+    /// The bytes taken up by blocks `start..end` in the data store.
+    ///
+    /// The end is the start plus the sizes of the largest subtrees that
+    /// cover the blocks.
+    pub async fn byte_range(&mut self, start: u64, end: u64) -> Result<Range<u64>> {
+        let from = self.byte_offset(start).await?;
+        let mut to = from;
+        let mut block = start;
+        while block < end {
+            let mut span = 1;
+            while block % (span * 2) == 0 && block + span * 2 <= end {
+                span *= 2;
+            }
+            to += self.spanned_size(2 * block + span - 1).await?;
+            block += span;
+        }
+        Ok(from..to)
+    }
+
+    /// The byte size of the blocks below the node at `index`, summed up
+    /// from its descendants if it is not stored.
+    async fn spanned_size(&mut self, index: u64) -> Result<u64> {
+        let mut size = 0;
+        let mut nodes = vec![index];
+        while let Some(index) = nodes.pop() {
+            match self.stored_node_size(index).await? {
+                Some(node_size) => size += node_size,
+                None if depth(index) > 0 => {
+                    let (left, right) = children(index);
+                    nodes.push(left);
+                    nodes.push(right);
+                }
+                None => bail!("Tree node {} is not stored", index),
+            }
+        }
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roots_of_leaves() {
+        assert!(full_roots(0).is_empty());
+        assert_eq!(full_roots(2), vec![0]);
+        assert_eq!(full_roots(4), vec![1]);
+        assert_eq!(full_roots(6), vec![1, 4]);
+        assert_eq!(full_roots(8), vec![3]);
+        assert_eq!(full_roots(10), vec![3, 8]);
+        assert_eq!(full_roots(14), vec![3, 9, 12]);
+        assert_eq!(full_roots(32), vec![15]);
+    }
+
+    #[test]
+    fn node_arithmetic() {
+        let depths: Vec<u32> = [0, 1, 2, 3, 5, 7, 11, 15]
+            .iter()
+            .map(|i| depth(*i))
+            .collect();
+        assert_eq!(depths, vec![0, 1, 0, 2, 1, 3, 2, 4]);
+        assert_eq!(children(1), (0, 2));
+        assert_eq!(children(3), (1, 5));
+        assert_eq!(children(5), (4, 6));
+        assert_eq!(children(7), (3, 11));
+        assert_eq!(children(11), (9, 13));
+        let first: Vec<u64> = [0, 1, 3, 5, 9, 11, 13]
+            .iter()
+            .map(|i| first_block(*i))
+            .collect();
+        assert_eq!(first, vec![0, 0, 0, 2, 4, 4, 6]);
+    }
+
+    #[test]
+    fn hash_leaves() {
+        assert_eq!(
+            hex::encode(leaf_hash(b"hello")),
+            "6717b25f24d96ccbc95166bacbb671d59eb4263ee5e1aa0f6b1520815cbee80b"
+        );
+        assert_eq!(
+            hex::encode(leaf_hash(b"")),
+            "5187b7a8021bf4f2c004ea3a54cfece1754f11c7624d2363c7f4cf4fddd1441e"
+        );
+    }
+
+    #[test]
+    fn read_stored_nodes() {
+        let mut tree = vec![0; (HEADER_SIZE + 2 * NODE_SIZE) as usize];
+        assert!(!has_stored_nodes(&tree));
+        let node = (HEADER_SIZE + NODE_SIZE) as usize;
+        tree[node] = 1;
+        tree[node + 39] = 7;
+        assert!(has_stored_nodes(&tree));
+        assert_eq!(stored_node(&tree, 0), None);
+        let (hash, size) = stored_node(&tree, 1).unwrap();
+        assert_eq!((hash[0], size), (1, 7));
+        assert_eq!(stored_node(&tree, 2), None);
+    }
+}
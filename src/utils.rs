@@ -8,3 +8,48 @@ pub fn set_panic_hook() {
     #[cfg(feature = "console_error_panic_hook")]
     console_error_panic_hook::set_once();
 }
+
+/// Set the panic hook and route `log` to the browser console. Safe to call
+/// from every exported entry point.
+pub fn init() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    set_panic_hook();
+    INIT.call_once(|| {
+        // The app may have installed a logger already, which is kept.
+        console_log::init_with_level(log::Level::Debug).ok();
+    });
+}
+
+/// Convert an error from the replication or feed code into a JS error.
+///
+/// Storage, replication and filesystem failures keep the name of their error
+/// type so that the app can tell e.g. a full quota apart from a closed
+/// connection.
+pub fn to_js_error(err: &anyhow::Error) -> wasm_bindgen::JsValue {
+    for cause in err.chain() {
+        if let Some(storage_err) = cause.downcast_ref::<crate::persistence::StorageError>() {
+            return storage_err.clone().into();
+        }
+        if let Some(replication_err) = cause.downcast_ref::<crate::hypercore::ReplicationError>() {
+            return replication_err.clone().into();
+        }
+        if let Some(drive_err) = cause.downcast_ref::<crate::drive::DriveError>() {
+            return drive_err.clone().into();
+        }
+    }
+    js_sys::Error::new(&format!("{:#}", err)).into()
+}
+
+/// The largest integer a JS number holds exactly.
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// Convert a JS number passed as an index, offset or length. Negative,
+/// fractional and non-finite numbers, and ones too large to be exact, are
+/// rejected with a `RangeError` naming the argument.
+pub fn js_index(value: f64, name: &str) -> Result<u64, wasm_bindgen::JsValue> {
+    if !(0.0..=MAX_SAFE_INTEGER).contains(&value) || value.fract() != 0.0 {
+        let message = format!("{} must be a non-negative integer, got {}", name, value);
+        return Err(js_sys::RangeError::new(&message).into());
+    }
+    Ok(value as u64)
+}
@@ -1,4 +1,5 @@
 use futures::channel::{mpsc, oneshot};
+use futures::future::{self, Either};
 use futures::prelude::*;
 use futures::ready;
 use futures::stream::{IntoAsyncRead, StreamExt, TryStreamExt};
@@ -12,7 +13,7 @@ use std::task::{Context, Poll};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
-use web_sys::{BinaryType, ErrorEvent, MessageEvent, WebSocket};
+use web_sys::{BinaryType, CloseEvent, ErrorEvent, MessageEvent, WebSocket};
 
 pub struct WebsocketStream {
     read_half: ReadHalf,
@@ -38,13 +39,36 @@ impl WebsocketStream {
         let mut open_tx = Some(open_tx);
         // Create onopen callback.
         let onopen_closure: Box<dyn FnMut(JsValue)> = Box::new(move |_| {
-            open_tx.take().unwrap().send(true).unwrap();
+            if let Some(open_tx) = open_tx.take() {
+                open_tx.send(()).ok();
+            }
         });
         let onopen_closure = Closure::wrap(onopen_closure);
         ws.set_onopen(Some(onopen_closure.as_ref().unchecked_ref()));
         onopen_closure.forget();
 
-        open_rx.await.unwrap();
+        // The read half's close handler isn't set yet, so a socket that fails
+        // to open is noticed here.
+        let (failed_tx, failed_rx) = oneshot::channel();
+        let mut failed_tx = Some(failed_tx);
+        let onclose_closure = Closure::wrap(Box::new(move |e: CloseEvent| {
+            if let Some(failed_tx) = failed_tx.take() {
+                failed_tx.send(e.code()).ok();
+            }
+        }) as Box<dyn FnMut(CloseEvent)>);
+        ws.set_onclose(Some(onclose_closure.as_ref().unchecked_ref()));
+        onclose_closure.forget();
+
+        match future::select(open_rx, failed_rx).await {
+            Either::Left((Ok(()), _)) => {}
+            Either::Right((Ok(code), _)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("Websocket closed before opening with code {}", code),
+                ));
+            }
+            _ => return Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+        }
         info!("Websocket opened on address {}", &addr);
 
         let read_half = ReadHalf::new(ws.clone());
@@ -75,8 +99,11 @@ impl WriteHalf {
                     .send_with_u8_array(&message)
                     .map_err(into_io_error)
                     .map(|_| ());
-                signal_tx.send(res).unwrap();
+                // The writer may have given up waiting.
+                signal_tx.send(res).ok();
             }
+            // The write half was dropped, e.g. with the protocol.
+            socket.close().ok();
         });
         Self {
             send_tx,
@@ -140,7 +167,8 @@ impl ReadHalf {
                 match message {
                     Ok(message) => {
                         // debug!("RECV {} {:?}", message.len(), message);
-                        inbound_tx.unbounded_send(Ok(message)).unwrap();
+                        // Fails only if the reader was dropped already.
+                        inbound_tx.unbounded_send(Ok(message)).ok();
                     }
                     Err(e) => error!("Could not cast websocket message to bytes: {:?}", e),
                 }
@@ -150,6 +178,20 @@ impl ReadHalf {
         socket.set_onmessage(Some(onmessage_callback.as_ref().unchecked_ref()));
         // Forget the callback to keep it alive.
         onmessage_callback.forget();
+        // End the stream when the socket closes, with an error unless the
+        // close was clean.
+        let onclose_callback = Closure::wrap(Box::new(move |e: CloseEvent| {
+            if !e.was_clean() {
+                let err = io::Error::new(
+                    io::ErrorKind::ConnectionReset,
+                    format!("Websocket closed with code {}", e.code()),
+                );
+                inbound_tx.unbounded_send(Err(err)).ok();
+            }
+            inbound_tx.close_channel();
+        }) as Box<dyn FnMut(CloseEvent)>);
+        socket.set_onclose(Some(onclose_callback.as_ref().unchecked_ref()));
+        onclose_callback.forget();
         let reader = inbound_rx.into_async_read();
         Self { reader }
     }